@@ -0,0 +1,101 @@
+use regex::Regex;
+use std::collections::{HashMap, VecDeque};
+
+use super::errors::{LogyError, Result};
+
+/// What makes two logs duplicates of each other
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DedupKey {
+    /// whole log text
+    Entry,
+    /// whole log text from the same source file
+    EntrySource,
+    /// captured group `key` or the first group of pattern, logs not matched are kept
+    Capture(String),
+}
+
+/// Options to remove duplicate logs
+#[derive(Debug, Clone)]
+pub struct DedupOptions {
+    pub key: DedupKey,
+    // milliseconds of log time a duplicate may come after the first one
+    pub window: i64,
+}
+
+/// Drops logs whose key has been seen within the time window
+pub(crate) struct Deduplicator {
+    key: DedupKey,
+    pattern: Option<Regex>,
+    window: i64,
+    // log time of first log by key
+    seen: HashMap<String, i64>,
+    // keys in order of first seen, to forget keys out of window
+    expiry: VecDeque<(i64, String)>,
+    removed: u64,
+}
+
+impl Deduplicator {
+    pub fn new(options: &DedupOptions) -> Result<Deduplicator> {
+        let pattern = match &options.key {
+            DedupKey::Capture(pattern) => {
+                Some(Regex::new(pattern).map_err(|e| LogyError::pattern(pattern, e))?)
+            }
+            _ => None,
+        };
+        Ok(Deduplicator {
+            key: options.key.clone(),
+            pattern,
+            window: options.window.max(0),
+            seen: HashMap::new(),
+            expiry: VecDeque::new(),
+            removed: 0,
+        })
+    }
+
+    /// check log is not a duplicate, remember it when it is the first one
+    pub fn accept(&mut self, time: i64, file: &str, log: &str) -> bool {
+        while let Some((first_time, _)) = self.expiry.front() {
+            if *first_time >= time - self.window {
+                break;
+            }
+            let (first_time, key) = self.expiry.pop_front().unwrap();
+            if self.seen.get(&key) == Some(&first_time) {
+                self.seen.remove(&key);
+            }
+        }
+
+        let key = match self.key(file, log) {
+            Some(key) => key,
+            None => return true,
+        };
+        match self.seen.get(&key) {
+            Some(first_time) if (time - first_time).abs() <= self.window => {
+                self.removed += 1;
+                false
+            }
+            _ => {
+                self.seen.insert(key.clone(), time);
+                self.expiry.push_back((time, key));
+                true
+            }
+        }
+    }
+
+    fn key(&self, file: &str, log: &str) -> Option<String> {
+        match &self.key {
+            DedupKey::Entry => Some(log.to_string()),
+            DedupKey::EntrySource => Some(format!("{}\n{}", file, log)),
+            DedupKey::Capture(_) => self
+                .pattern
+                .as_ref()
+                .and_then(|pattern| pattern.captures(log))
+                .and_then(|captures| captures.name("key").or_else(|| captures.get(1)))
+                .map(|key| key.as_str().to_string()),
+        }
+    }
+
+    /// count of duplicates dropped
+    pub fn removed(&self) -> u64 {
+        self.removed
+    }
+}
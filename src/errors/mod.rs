@@ -0,0 +1,149 @@
+use log::{info, warn};
+use std::{any::Any, fmt::Display, io, result, str::FromStr};
+
+pub type Result<T> = result::Result<T, LogyError>;
+
+#[derive(Debug)]
+pub enum LogyError {
+    /// failed to read or write a file
+    Io { file: String, cause: io::Error },
+    /// invalid regular expression in arguments
+    Pattern {
+        pattern: String,
+        cause: regex::Error,
+    },
+    /// invalid value of command argument
+    Argument { name: String, value: String },
+    /// log can not be parsed, line number starts from 1
+    MalformedLog {
+        file: String,
+        line_number: u64,
+        cause: String,
+    },
+    /// zstd dictionary can not be trained from samples
+    Dictionary { cause: io::Error },
+    /// a thread reading or merging logs stopped unexpectedly
+    Panic { cause: String },
+}
+
+impl LogyError {
+    pub fn io(file: &str, cause: io::Error) -> LogyError {
+        LogyError::Io {
+            file: file.to_string(),
+            cause,
+        }
+    }
+
+    pub fn pattern(pattern: &str, cause: regex::Error) -> LogyError {
+        LogyError::Pattern {
+            pattern: pattern.to_string(),
+            cause,
+        }
+    }
+
+    pub fn argument(name: &str, value: &str) -> LogyError {
+        LogyError::Argument {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    pub fn malformed(file: &str, line_number: u64, cause: &str) -> LogyError {
+        LogyError::MalformedLog {
+            file: file.to_string(),
+            line_number,
+            cause: cause.to_string(),
+        }
+    }
+
+    pub fn dictionary(cause: io::Error) -> LogyError {
+        LogyError::Dictionary { cause }
+    }
+
+    /// error of thread panicked with `payload`
+    pub fn panic(payload: Box<dyn Any + Send>) -> LogyError {
+        let cause = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload
+                .downcast_ref::<&str>()
+                .map_or("unknown cause".to_string(), |message| message.to_string()),
+        };
+        LogyError::Panic { cause }
+    }
+}
+
+impl Display for LogyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogyError::Io { file, cause } => write!(f, "{}: {}", file, cause),
+            LogyError::Pattern { pattern, cause } => {
+                write!(f, "invalid pattern {}: {}", pattern, cause)
+            }
+            LogyError::Argument { name, value } => {
+                write!(f, "invalid value of {}: {}", name, value)
+            }
+            LogyError::MalformedLog {
+                file,
+                line_number,
+                cause,
+            } => write!(f, "{}:{}: {}", file, line_number, cause),
+            LogyError::Dictionary { cause } => write!(f, "failed to train dictionary: {}", cause),
+            LogyError::Panic { cause } => write!(f, "failed to read logs: {}", cause),
+        }
+    }
+}
+
+impl std::error::Error for LogyError {}
+
+/// How to deal with malformed logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// drop malformed log silently
+    Skip,
+    /// drop malformed log and report it
+    Warn,
+    /// stop processing
+    Abort,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = LogyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(ErrorPolicy::Skip),
+            "warn" => Ok(ErrorPolicy::Warn),
+            "abort" => Ok(ErrorPolicy::Abort),
+            _ => Err(LogyError::argument("on-error", s)),
+        }
+    }
+}
+
+/// Applies error policy to malformed logs and counts the skipped ones
+pub struct ErrorHandler {
+    policy: ErrorPolicy,
+    skipped: u64,
+}
+
+impl ErrorHandler {
+    pub fn new(policy: ErrorPolicy) -> ErrorHandler {
+        ErrorHandler { policy, skipped: 0 }
+    }
+
+    /// returns error back when policy is abort, otherwise the log is counted as skipped
+    pub fn handle(&mut self, error: LogyError) -> Result<()> {
+        match self.policy {
+            ErrorPolicy::Abort => return Err(error),
+            ErrorPolicy::Warn => warn!("skip malformed log, {}", error),
+            ErrorPolicy::Skip => {}
+        }
+        self.skipped += 1;
+        Ok(())
+    }
+
+    pub fn report(&self) {
+        if self.skipped > 0 {
+            info!("{} malformed logs skipped", self.skipped);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use regex::{Regex, RegexBuilder};
+
+use super::errors::{LogyError, Result};
+use super::models::{field_pointer, json_text, parse_json, JsonFields};
+
+// levels from low to high, aliases share the same rank
+const LEVELS: [(&str, u8); 10] = [
+    ("TRACE", 0),
+    ("DEBUG", 1),
+    ("INFO", 2),
+    ("WARN", 3),
+    ("WARNING", 3),
+    ("ERROR", 4),
+    ("ERR", 4),
+    ("FATAL", 5),
+    ("CRITICAL", 5),
+    ("PANIC", 5),
+];
+
+/// Content filters applied to whole logs
+#[derive(Debug, Clone, Default)]
+pub struct FilterOptions {
+    // log is kept when any of the patterns matches
+    pub grep: Vec<String>,
+    // log is dropped when any of the patterns matches
+    pub grep_v: Vec<String>,
+    // minimal level of logs to keep, logs without level are dropped
+    pub level: Option<String>,
+    // level is captured by group `level` or the first group, matched against first line of log
+    pub level_pattern: Option<String>,
+}
+
+/// Decides which logs are kept in output
+pub(crate) struct LogFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    level_pattern: Regex,
+    // JSON pointer of level in JSON logs
+    level_field: Option<String>,
+    threshold: Option<u8>,
+}
+
+impl LogFilter {
+    pub fn new(options: &FilterOptions, json: &JsonFields) -> Result<LogFilter> {
+        let threshold = match &options.level {
+            Some(level) => {
+                Some(level_rank(level).ok_or_else(|| LogyError::argument("level", level))?)
+            }
+            None => None,
+        };
+        let level_pattern = match &options.level_pattern {
+            Some(level_pattern) => level_pattern.to_string(),
+            None => default_level_pattern(),
+        };
+
+        Ok(LogFilter {
+            include: compile_all(&options.grep)?,
+            exclude: compile_all(&options.grep_v)?,
+            level_pattern: RegexBuilder::new(&level_pattern)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| LogyError::pattern(&level_pattern, e))?,
+            level_field: json.level.as_deref().map(field_pointer),
+            threshold,
+        })
+    }
+
+    /// check log with all of its lines
+    pub fn accept(&self, log: &str) -> bool {
+        if !self.include.is_empty() && !self.include.iter().any(|re| re.is_match(log)) {
+            return false;
+        }
+        if self.exclude.iter().any(|re| re.is_match(log)) {
+            return false;
+        }
+        match self.threshold {
+            Some(threshold) => self.level(log).is_some_and(|rank| rank >= threshold),
+            None => true,
+        }
+    }
+
+    fn level(&self, log: &str) -> Option<u8> {
+        if let Some(level_field) = &self.level_field {
+            if let Some(object) = parse_json(log) {
+                return object
+                    .pointer(level_field)
+                    .and_then(json_text)
+                    .and_then(|level| level_rank(&level));
+            }
+        }
+        let first_line = log.lines().next().unwrap_or_default();
+        self.level_pattern
+            .captures(first_line)
+            .and_then(|captures| captures.name("level").or_else(|| captures.get(1)))
+            .and_then(|level| level_rank(level.as_str()))
+    }
+}
+
+fn compile_all(patterns: &[String]) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|pattern| Regex::new(pattern).map_err(|e| LogyError::pattern(pattern, e)))
+        .collect()
+}
+
+/// pattern captures any of known levels as the first group
+fn default_level_pattern() -> String {
+    let names = LEVELS.iter().map(|&(name, _)| name).collect::<Vec<&str>>();
+    format!(r"\b({})\b", names.join("|"))
+}
+
+fn level_rank(level: &str) -> Option<u8> {
+    LEVELS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(level))
+        .map(|&(_, rank)| rank)
+}
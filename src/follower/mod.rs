@@ -0,0 +1,296 @@
+use log::{debug, info};
+use regex::Regex;
+use std::{
+    collections::BTreeSet,
+    fs::{self, File, Metadata},
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{self, Instant},
+};
+
+use super::errors::{ErrorHandler, ErrorPolicy, LogyError, Result};
+use super::models::{LogLine, LogTimeParser, TimeZones, WrappedFileWriter, WriterOptions, STDIO};
+
+pub struct TailOptions {
+    // prefix pattern to determine start of log
+    pub pattern: String,
+    pub log_time_format: String,
+    pub output_file_pattern: String,
+    // keep watching files for new logs
+    pub follow: bool,
+    // read existing logs instead of starting from end of files
+    pub from_beginning: bool,
+    // logs are held within this milliseconds of log time to be merged in order
+    pub reorder_window: i64,
+    // all held logs are emitted when no log comes within this milliseconds of wall clock
+    pub release_timeout: u64,
+    // last log of a file is emitted when no more lines come within this milliseconds
+    pub flush_timeout: u64,
+    pub error_policy: ErrorPolicy,
+    // milliseconds of time period per output file
+    pub bucket: Option<i64>,
+    // time zones of log times without offset, output files are split by local time of default one
+    pub time_zones: TimeZones,
+}
+
+/// merge logs of growing files in log time order until `running` becomes false
+pub fn tail_logs(files: &[&str], options: &TailOptions, running: &AtomicBool) -> Result<()> {
+    // stdin can not be reopened or polled like a file, piped logs are merged by reduce
+    if files.contains(&STDIO) {
+        return Err(LogyError::argument("files", STDIO));
+    }
+    let parser = LogTimeParser::new(&options.pattern, &options.log_time_format)?
+        .with_time_zones(&options.time_zones);
+    let mut writer = WrappedFileWriter::new(
+        &options.output_file_pattern,
+        WriterOptions {
+            bucket: options.bucket,
+            time_zone: options.time_zones.default,
+            ..WriterOptions::new(0)
+        },
+    )?;
+    let mut error_handler = ErrorHandler::new(options.error_policy);
+    let mut readers = files
+        .iter()
+        .map(|&file| FollowReader::new(file, &options.pattern, options.from_beginning))
+        .collect::<Result<Vec<FollowReader>>>()?;
+    let flush_timeout = time::Duration::from_millis(options.flush_timeout);
+    let release_timeout = time::Duration::from_millis(options.release_timeout);
+
+    let mut pending: BTreeSet<LogLine> = BTreeSet::new();
+    let mut seq: u64 = 0;
+    // the latest log time received
+    let mut watermark = i64::MIN;
+    let mut last_received = Instant::now();
+
+    loop {
+        let following = options.follow && running.load(Ordering::Relaxed);
+        let mut received = false;
+        for reader in readers.iter_mut() {
+            // flush all buffered logs when this is the last round
+            let timeout = if following {
+                flush_timeout
+            } else {
+                time::Duration::from_secs(0)
+            };
+            for (line_number, line) in reader.poll(timeout)? {
+                match parser.parse_file_log(&reader.path, &line) {
+                    Ok(time) => {
+                        seq += 1;
+                        watermark = watermark.max(time);
+                        pending.insert(LogLine::new(time, &reader.path, seq, &line));
+                        received = true;
+                    }
+                    Err(cause) => error_handler.handle(LogyError::malformed(
+                        &reader.path,
+                        line_number,
+                        &cause,
+                    ))?,
+                }
+            }
+        }
+        if received {
+            last_received = Instant::now();
+        }
+
+        // release logs out of reorder window, or all of them when no more logs coming
+        let release_all = !following || last_received.elapsed() >= release_timeout;
+        let mut released = false;
+        while let Some(log) = pending.iter().next() {
+            if !release_all && log.time() > watermark - options.reorder_window {
+                break;
+            }
+            let log = pending.pop_first().unwrap();
+            writer.write(log.time(), &log.value())?;
+            released = true;
+        }
+        if released {
+            writer.flush()?;
+        }
+
+        if !following {
+            break;
+        }
+        if !received {
+            thread::sleep(time::Duration::from_millis(100));
+        }
+    }
+
+    writer.flush()?;
+    error_handler.report();
+    Ok(())
+}
+
+/// Reads new logs appended to a file, file rotated by rename or truncate is reopened
+pub(crate) struct FollowReader {
+    path: String,
+    pattern: Regex,
+    reader: Option<BufReader<File>>,
+    // identity of opened file
+    file_id: Option<u64>,
+    // bytes read from opened file
+    position: u64,
+    // line without line break yet
+    partial: String,
+    // lines of last log
+    buffer: Vec<String>,
+    // count of lines read from opened file
+    read_count: u64,
+    // line number of first line in buffer
+    buffer_line_number: u64,
+    // time of last line added to buffer
+    last_read: Instant,
+}
+
+impl FollowReader {
+    pub fn new(path: &str, pattern: &str, from_beginning: bool) -> Result<FollowReader> {
+        let mut reader = FollowReader {
+            path: path.to_string(),
+            pattern: Regex::new(pattern).map_err(|e| LogyError::pattern(pattern, e))?,
+            reader: None,
+            file_id: None,
+            position: 0,
+            partial: String::new(),
+            buffer: Vec::new(),
+            read_count: 0,
+            buffer_line_number: 0,
+            last_read: Instant::now(),
+        };
+        reader.open()?;
+        if !from_beginning {
+            reader.skip_to_end()?;
+        }
+        Ok(reader)
+    }
+
+    /// open file from beginning, file may not exist yet while rotating
+    fn open(&mut self) -> Result<()> {
+        match File::open(&self.path) {
+            Ok(file) => {
+                let metadata = file.metadata().map_err(|e| LogyError::io(&self.path, e))?;
+                debug!("open file {} to follow", self.path);
+                self.file_id = Some(file_id(&metadata));
+                self.reader = Some(BufReader::new(file));
+                self.position = 0;
+                self.read_count = 0;
+                Ok(())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.reader = None;
+                self.file_id = None;
+                Ok(())
+            }
+            Err(e) => Err(LogyError::io(&self.path, e)),
+        }
+    }
+
+    fn skip_to_end(&mut self) -> Result<()> {
+        if let Some(reader) = self.reader.as_mut() {
+            self.position = reader
+                .seek(SeekFrom::End(0))
+                .map_err(|e| LogyError::io(&self.path, e))?;
+        }
+        Ok(())
+    }
+
+    /// read appended lines, returns completed logs with their line numbers
+    pub fn poll(&mut self, flush_timeout: time::Duration) -> Result<Vec<(u64, String)>> {
+        let mut logs = Vec::new();
+        self.read_available(&mut logs)?;
+
+        // file is replaced or truncated, old file has been read to end already
+        if self.rotated()? {
+            info!("file {} rotated, reopen it", self.path);
+            if !self.partial.is_empty() {
+                let line = std::mem::take(&mut self.partial).trim_end().to_string();
+                self.buffer.push(line);
+            }
+            self.open()?;
+            self.read_available(&mut logs)?;
+        }
+
+        if !self.buffer.is_empty() && self.last_read.elapsed() >= flush_timeout {
+            if !self.partial.is_empty() {
+                // writer may never finish this line
+                let line = std::mem::take(&mut self.partial);
+                self.buffer.push(line);
+            }
+            logs.push(self.take_buffer());
+        }
+        Ok(logs)
+    }
+
+    fn read_available(&mut self, logs: &mut Vec<(u64, String)>) -> Result<()> {
+        let mut reader = match self.reader.take() {
+            Some(reader) => reader,
+            None => return Ok(()),
+        };
+        let result = self.read_lines(&mut reader, logs);
+        self.reader = Some(reader);
+        result
+    }
+
+    fn read_lines(
+        &mut self,
+        reader: &mut BufReader<File>,
+        logs: &mut Vec<(u64, String)>,
+    ) -> Result<()> {
+        loop {
+            let mut line = String::new();
+            let size = reader
+                .read_line(&mut line)
+                .map_err(|e| LogyError::io(&self.path, e))?;
+            if size == 0 {
+                return Ok(());
+            }
+            self.position += size as u64;
+            self.partial.push_str(&line);
+            if !self.partial.ends_with('\n') {
+                // wait for rest of line
+                continue;
+            }
+
+            let line = std::mem::take(&mut self.partial).trim_end().to_string();
+            self.read_count += 1;
+            self.last_read = Instant::now();
+            if self.pattern.is_match(&line) && !self.buffer.is_empty() {
+                // next log, return all of previous lines
+                logs.push(self.take_buffer());
+            }
+            if self.buffer.is_empty() {
+                self.buffer_line_number = self.read_count;
+            }
+            self.buffer.push(line);
+        }
+    }
+
+    fn take_buffer(&mut self) -> (u64, String) {
+        let full_log = self.buffer.join("\n");
+        self.buffer.clear();
+        (self.buffer_line_number, full_log)
+    }
+
+    /// check whether file on path is another file or shorter than read
+    fn rotated(&self) -> Result<bool> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // removed and not created yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(LogyError::io(&self.path, e)),
+        };
+        Ok(self.file_id != Some(file_id(&metadata)) || metadata.len() < self.position)
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> u64 {
+    // rename rotation can not be detected, truncation still works
+    0
+}
@@ -0,0 +1,246 @@
+use log::debug;
+use std::{io::BufRead, str::FromStr};
+
+use super::errors::{LogyError, Result};
+use super::models::{open_source, JsonFields, LogTimeParser};
+
+/// Layout of logs, defines start of log, log time and optional captures of level, thread and logger
+///
+/// Log time is captured by group `time`, or the first group when not named.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogFormat {
+    pub name: &'static str,
+    // prefix pattern to determine start of log
+    pub pattern: &'static str,
+    // chrono format of captured log time
+    pub time_format: &'static str,
+    // fields of JSON logs
+    pub time_field: Option<&'static str>,
+    pub level_field: Option<&'static str>,
+}
+
+impl LogFormat {
+    const fn text(name: &'static str, pattern: &'static str, time_format: &'static str) -> Self {
+        LogFormat {
+            name,
+            pattern,
+            time_format,
+            time_field: None,
+            level_field: None,
+        }
+    }
+
+    /// prefix pattern captures level of log
+    pub fn has_level(&self) -> bool {
+        self.pattern.contains("(?P<level>")
+    }
+}
+
+pub const FORMATS: [LogFormat; 11] = [
+    LogFormat::text(
+        "default",
+        r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#,
+        "%Y-%m-%d %H:%M:%S%.3f",
+    ),
+    // %d{yyyy-MM-dd HH:mm:ss.SSS} [%thread] %-5level %logger{36} - %msg%n
+    LogFormat::text(
+        "logback",
+        r#"^(?P<time>\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}\.\d{3}) \[(?P<thread>[^\]]+)\] +(?P<level>[A-Z]+) +(?P<logger>\S+)"#,
+        "%Y-%m-%d %H:%M:%S%.3f",
+    ),
+    // %d [%t] %-5level %logger{36} - %msg%n
+    LogFormat::text(
+        "log4j2",
+        r#"^(?P<time>\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2},\d{3}) \[(?P<thread>[^\]]+)\] +(?P<level>[A-Z]+) +(?P<logger>\S+)"#,
+        "%Y-%m-%d %H:%M:%S,%3f",
+    ),
+    LogFormat::text(
+        "spring-boot",
+        r#"^(?P<time>\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}\.\d{3}) +(?P<level>[A-Z]+) +\d+ --- \[ *(?P<thread>[^\]]*)\] (?P<logger>\S+) +:"#,
+        "%Y-%m-%d %H:%M:%S%.3f",
+    ),
+    LogFormat::text(
+        "nginx-access",
+        r#"^(?P<host>\S+) - (?P<user>\S+) \[(?P<time>[^\]]+)\] "[^"]*" (?P<status>\d{3}) "#,
+        "%d/%b/%Y:%H:%M:%S %z",
+    ),
+    LogFormat::text(
+        "apache-combined",
+        r#"^(?P<host>\S+) (?P<ident>\S+) (?P<user>\S+) \[(?P<time>[^\]]+)\] "[^"]*" (?P<status>\d{3}) "#,
+        "%d/%b/%Y:%H:%M:%S %z",
+    ),
+    // year is not logged, current year is assumed
+    LogFormat::text(
+        "syslog-rfc3164",
+        r#"^(?:<\d+>)?(?P<time>[A-Z][a-z]{2} [ \d]\d \d{2}:\d{2}:\d{2}) (?P<host>\S+) (?P<logger>[^:\[\s]+)(?:\[\d+\])?:"#,
+        "%b %e %H:%M:%S",
+    ),
+    LogFormat::text(
+        "syslog-rfc5424",
+        r#"^<\d+>1 (?P<time>\d{4}-\d{2}-\d{2}T\S+) (?P<host>\S+) (?P<logger>\S+) "#,
+        "%+",
+    ),
+    // %(asctime)s - %(name)s - %(levelname)s - %(message)s
+    LogFormat::text(
+        "python-logging",
+        r#"^(?P<time>\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2},\d{3}) - (?P<logger>\S+) - (?P<level>[A-Z]+) - "#,
+        "%Y-%m-%d %H:%M:%S,%3f",
+    ),
+    // JSON of production encoder, or text of console encoder
+    LogFormat {
+        name: "go-zap",
+        pattern: r#"^(?P<time>\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}\.\d{3}[+-]\d{4})\t(?P<level>[A-Z]+)\t(?P<logger>\S+)"#,
+        time_format: "%Y-%m-%dT%H:%M:%S%.3f%z",
+        time_field: Some("ts"),
+        level_field: Some("level"),
+    },
+    // container runtime logs of kubernetes nodes
+    LogFormat::text(
+        "k8s-cri",
+        r#"^(?P<time>\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(?:\.\d+)?(?:Z|[+-]\d{2}:\d{2})) (?P<stream>stdout|stderr) [FP] "#,
+        "%+",
+    ),
+];
+
+// common layouts of log time at start of line, tried when no preset fits
+const LAYOUTS: [LogFormat; 8] = [
+    LogFormat::text(
+        "custom",
+        r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2},\d{3})"#,
+        "%Y-%m-%d %H:%M:%S,%3f",
+    ),
+    LogFormat::text(
+        "custom",
+        r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2})"#,
+        "%Y-%m-%d %H:%M:%S",
+    ),
+    LogFormat::text(
+        "custom",
+        r#"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(?:\.\d+)?(?:Z|[+-]\d{2}:\d{2}))"#,
+        "%+",
+    ),
+    LogFormat::text(
+        "custom",
+        r#"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}\.\d+)"#,
+        "%Y-%m-%dT%H:%M:%S%.f",
+    ),
+    LogFormat::text(
+        "custom",
+        r#"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2})"#,
+        "%Y-%m-%dT%H:%M:%S",
+    ),
+    LogFormat::text(
+        "custom",
+        r#"^(\d{4}/\d{2}/\d{2} \d{2}:\d{2}:\d{2})"#,
+        "%Y/%m/%d %H:%M:%S",
+    ),
+    LogFormat::text(
+        "custom",
+        r#"^\[(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}\.\d{3})\]"#,
+        "%Y-%m-%d %H:%M:%S%.3f",
+    ),
+    LogFormat::text(
+        "custom",
+        r#"^\[?[A-Za-z]+\]? +(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}\.\d{3})"#,
+        "%Y-%m-%d %H:%M:%S%.3f",
+    ),
+];
+
+// common time fields of JSON logs
+const JSON_TIME_FIELDS: [&str; 4] = ["time", "timestamp", "@timestamp", "ts"];
+
+/// A candidate format and how many sampled lines it parses
+#[derive(Debug, Clone, Copy)]
+pub struct Detection {
+    pub format: LogFormat,
+    // lines with log time parsed
+    pub matched: usize,
+    // non-empty lines sampled
+    pub total: usize,
+}
+
+impl Detection {
+    pub fn rate(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.matched as f64 / self.total as f64
+        }
+    }
+}
+
+/// try presets and common layouts on the first lines of file, best candidate first
+///
+/// Candidates parse none of lines are not returned, candidates with same match rate are ordered by
+/// prefix pattern length, as longer one is more specific.
+pub fn detect(
+    file: &str,
+    sample_lines: usize,
+    zstd_dictionary: Option<&[u8]>,
+) -> Result<Vec<Detection>> {
+    let reader = open_source(file, zstd_dictionary)?;
+    let mut lines = Vec::new();
+    for line in reader.lines().take(sample_lines) {
+        let line = line.map_err(|e| LogyError::io(file, e))?;
+        if !line.trim().is_empty() {
+            lines.push(line.trim_end().to_string());
+        }
+    }
+
+    // lines of JSON object only
+    let json_formats = JSON_TIME_FIELDS.iter().map(|&field| LogFormat {
+        name: "json",
+        pattern: r"^\{",
+        time_field: Some(field),
+        level_field: Some("level"),
+        ..FORMATS[0]
+    });
+    let mut detections = Vec::new();
+    for format in FORMATS.iter().copied().chain(LAYOUTS).chain(json_formats) {
+        let parser =
+            LogTimeParser::new(format.pattern, format.time_format)?.with_json_fields(&JsonFields {
+                time: format.time_field.map(|field| field.to_string()),
+                ..JsonFields::default()
+            });
+        let matched = lines
+            .iter()
+            .filter(|line| parser.parse(line).is_ok())
+            .count();
+        debug!(
+            "format {} parses {}/{} lines",
+            format.name,
+            matched,
+            lines.len()
+        );
+        if matched > 0 {
+            detections.push(Detection {
+                format,
+                matched,
+                total: lines.len(),
+            });
+        }
+    }
+    detections.sort_by(|a, b| {
+        b.matched
+            .cmp(&a.matched)
+            .then(b.format.pattern.len().cmp(&a.format.pattern.len()))
+    });
+    Ok(detections)
+}
+
+/// names of all formats
+pub fn format_names() -> Vec<&'static str> {
+    FORMATS.iter().map(|format| format.name).collect()
+}
+
+impl FromStr for LogFormat {
+    type Err = LogyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        FORMATS
+            .iter()
+            .find(|format| format.name == s)
+            .copied()
+            .ok_or_else(|| LogyError::argument("format", s))
+    }
+}
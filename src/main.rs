@@ -1,30 +1,110 @@
-use clap::{App, Arg, SubCommand};
-use log::{error, info};
-use std::io::Result;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::{error, info, warn};
+use std::{
+    fs, process, result,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
+use chrono_tz::Tz;
+use dedup::{DedupKey, DedupOptions};
+use errors::{LogyError, Result};
+use filter::FilterOptions;
+use formats::{format_names, LogFormat, FORMATS};
+use models::{parse_duration, parse_time, JsonFields, TimeRange, TimeZones, WriterOptions, STDIO};
+
+mod dedup;
+mod errors;
+mod filter;
+mod follower;
+mod formats;
 mod models;
 mod reducer;
+mod sorter;
+mod stats;
+mod tagger;
 #[cfg(test)]
 mod test;
 mod tracer;
+mod trainer;
+
+// lines sampled to detect format
+const DETECT_LINES: usize = 1000;
 
-fn main() -> Result<()> {
+fn main() {
     let mut app = command_args();
     let arg_matches = app.clone().get_matches();
 
     env_logger::init();
 
+    if let Err(e) = run(&mut app, &arg_matches) {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(app: &mut App, arg_matches: &ArgMatches) -> Result<()> {
     if let Some(args) = arg_matches.subcommand_matches("reduce") {
         if let Some(files) = args.values_of("files") {
+            let files = files.collect::<Vec<&str>>();
+            let zstd_dictionary = zstd_dictionary(args)?;
+            let format = log_format(args, &files, zstd_dictionary.as_deref())?;
+            let time_zones = time_zones(args)?;
+            let out_time_zone = optional_arg(args, "out-tz", str::parse::<Tz>)?;
             reducer::reduce_logs(
-                &files.collect(),
-                args.value_of("prefix").unwrap(),
-                args.value_of("log-time-format").unwrap(),
-                args.value_of("out-file-pattern").unwrap(),
-                args.value_of("compress-level")
-                    .unwrap()
-                    .parse::<u32>()
-                    .unwrap(),
+                &files,
+                &reducer::ReduceOptions {
+                    pattern: prefix(args, &format),
+                    log_time_format: log_time_format(args, &format),
+                    output_file_pattern: args.value_of("out-file-pattern").unwrap().to_string(),
+                    writer_options: WriterOptions {
+                        compress_level: parse_arg(args, "compress-level")?,
+                        codec: parse_arg(args, "codec")?,
+                        zstd_dictionary: zstd_dictionary.clone(),
+                        max_size: optional_arg(args, "max-size", parse_size)?,
+                        max_lines: optional_arg(args, "max-lines", str::parse)?,
+                        retention: optional_arg(args, "retain", str::parse)?,
+                        bucket: optional_arg(args, "bucket", parse_bucket)?,
+                        // captures of prefix, like {level} or {thread}, can be placeholders as well
+                        partition_by: Some(
+                            args.value_of("partition-by")
+                                .map_or_else(|| prefix(args, &format), |s| s.to_string()),
+                        ),
+                        max_open_files: Some(parse_arg(args, "max-open-files")?),
+                        // output files are split by local time of output time zone
+                        time_zone: Some(out_time_zone.unwrap_or_else(|| time_zones.default_zone())),
+                        compress_threads: optional_arg(args, "compress-threads", str::parse)?,
+                        compress_pool: None,
+                    },
+                    error_policy: parse_arg(args, "on-error")?,
+                    time_range: time_range(args, &time_zones)?,
+                    filter: FilterOptions {
+                        grep: values_of(args, "grep"),
+                        grep_v: values_of(args, "grep-v"),
+                        level: args.value_of("level").map(|s| s.to_string()),
+                        level_pattern: args
+                            .value_of("level-pattern")
+                            .or_else(|| Some(format.pattern).filter(|_| format.has_level()))
+                            .map(|s| s.to_string()),
+                    },
+                    json: json_fields(args, &format),
+                    out_time_zone,
+                    annotate_time: args.value_of("out-time") == Some("annotate"),
+                    time_zones,
+                    tag_source: args.value_of("tag-source").map(|s| s.to_string()),
+                    source_pattern: args.value_of("source-pattern").map(|s| s.to_string()),
+                    dedup: dedup_options(args)?,
+                    sort_memory: if args.is_present("sort") {
+                        optional_arg(args, "sort-memory", parse_size)?.map(|size| size as usize)
+                    } else {
+                        None
+                    },
+                    threads: optional_arg(args, "threads", str::parse)?,
+                    zstd_dictionary,
+                },
             )?;
             info!("task done");
         } else {
@@ -33,22 +113,108 @@ fn main() -> Result<()> {
         return Ok(());
     } else if let Some(args) = arg_matches.subcommand_matches("trace") {
         if let Some(files) = args.values_of("files") {
+            let files = files.collect::<Vec<&str>>();
+            let zstd_dictionary = zstd_dictionary(args)?;
+            let format = log_format(args, &files, zstd_dictionary.as_deref())?;
+            let time_zones = time_zones(args)?;
             tracer::trace_log(
-                &files.collect(),
-                args.value_of("minimal-cost-time")
-                    .unwrap()
-                    .parse::<i64>()
-                    .unwrap(),
-                args.value_of("prefix").unwrap(),
-                args.value_of("log-time-format").unwrap(),
-                args.value_of("trace-pattern").unwrap(),
-                args.value_of("out-file-pattern").unwrap(),
+                &files,
+                &tracer::TraceOptions {
+                    pattern: prefix(args, &format),
+                    log_time_format: log_time_format(args, &format),
+                    // trace ID may come from trace field only
+                    trace_pattern: args.value_of("trace-pattern").unwrap_or("").to_string(),
+                    output_file_pattern: args.value_of("out-file-pattern").unwrap().to_string(),
+                    min_cost_time: parse_arg(args, "minimal-cost-time")?,
+                    idle_timeout: parse_arg(args, "idle-timeout")?,
+                    memory_limit: optional_arg(args, "max-memory", parse_megabytes)?
+                        .unwrap_or_default(),
+                    error_policy: parse_arg(args, "on-error")?,
+                    merge: args.is_present("merge"),
+                    report_format: parse_arg(args, "report")?,
+                    stats: args.is_present("stats"),
+                    stats_hourly: args.is_present("stats-hourly"),
+                    start_pattern: args.value_of("start-pattern").map(|s| s.to_string()),
+                    end_pattern: args.value_of("end-pattern").map(|s| s.to_string()),
+                    bucket: optional_arg(args, "bucket", parse_bucket)?,
+                    time_range: time_range(args, &time_zones)?,
+                    json: json_fields(args, &format),
+                    time_zones,
+                    compress_level: parse_arg(args, "compress-level")?,
+                    codec: parse_arg(args, "codec")?,
+                    zstd_dictionary,
+                    tag_source: args.value_of("tag-source").map(|s| s.to_string()),
+                    source_pattern: args.value_of("source-pattern").map(|s| s.to_string()),
+                },
             )?;
             info!("task done");
         } else {
             error!("No source file provided");
         }
         return Ok(());
+    } else if let Some(args) = arg_matches.subcommand_matches("train-dict") {
+        if let Some(files) = args.values_of("files") {
+            let files = files.collect::<Vec<&str>>();
+            let format = log_format(args, &files, None)?;
+            let dictionary = trainer::train_dictionary(
+                &files,
+                &prefix(args, &format),
+                optional_arg(args, "max-size", parse_size)?.unwrap_or_default() as usize,
+            )?;
+            let output = args.value_of("out-file").unwrap();
+            fs::write(output, &dictionary).map_err(|e| LogyError::io(output, e))?;
+            info!(
+                "write dictionary of {} bytes to {}",
+                dictionary.len(),
+                output
+            );
+        }
+        return Ok(());
+    } else if let Some(args) = arg_matches.subcommand_matches("detect") {
+        if let Some(files) = args.values_of("files") {
+            for file in files {
+                print_detections(file, parse_arg(args, "lines")?)?;
+            }
+        }
+        return Ok(());
+    } else if let Some(args) = arg_matches.subcommand_matches("tail") {
+        if let Some(files) = args.values_of("files") {
+            let files = files.collect::<Vec<&str>>();
+            let format = log_format(args, &files, None)?;
+            let running = Arc::new(AtomicBool::new(true));
+            let handler_running = running.clone();
+            // stop following on interrupt to emit held logs, exit at once on second interrupt
+            if let Err(e) = ctrlc::set_handler(move || {
+                if !handler_running.swap(false, Ordering::Relaxed) {
+                    process::exit(130);
+                }
+            }) {
+                warn!(
+                    "fail to handle interrupt, held logs are lost on exit: {}",
+                    e
+                );
+            }
+            follower::tail_logs(
+                &files,
+                &follower::TailOptions {
+                    pattern: prefix(args, &format),
+                    log_time_format: log_time_format(args, &format),
+                    output_file_pattern: args.value_of("out-file-pattern").unwrap().to_string(),
+                    follow: args.is_present("follow"),
+                    from_beginning: args.is_present("from-beginning"),
+                    reorder_window: parse_arg(args, "reorder-window")?,
+                    release_timeout: parse_arg(args, "release-timeout")?,
+                    flush_timeout: parse_arg(args, "flush-timeout")?,
+                    error_policy: parse_arg(args, "on-error")?,
+                    bucket: optional_arg(args, "bucket", parse_bucket)?,
+                    time_zones: time_zones(args)?,
+                },
+                &running,
+            )?;
+        } else {
+            error!("No source file provided");
+        }
+        return Ok(());
     }
 
     app.print_help().unwrap();
@@ -57,6 +223,211 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// parse value of argument which has default value
+fn parse_arg<T: FromStr>(args: &ArgMatches, name: &str) -> Result<T> {
+    let value = args.value_of(name).unwrap();
+    value
+        .parse::<T>()
+        .map_err(|_| LogyError::argument(name, value))
+}
+
+/// parse value of argument which is optional
+fn optional_arg<T, E>(
+    args: &ArgMatches,
+    name: &str,
+    parse: impl Fn(&str) -> result::Result<T, E>,
+) -> Result<Option<T>> {
+    args.value_of(name)
+        .map(|value| parse(value).map_err(|_| LogyError::argument(name, value)))
+        .transpose()
+}
+
+fn values_of(args: &ArgMatches, name: &str) -> Vec<String> {
+    args.values_of(name)
+        .map(|values| values.map(|s| s.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// format given, or detected from the first file when format is auto and prefix is not given
+fn log_format(
+    args: &ArgMatches,
+    files: &[&str],
+    zstd_dictionary: Option<&Vec<u8>>,
+) -> Result<LogFormat> {
+    let name = args.value_of("format").unwrap();
+    if name != "auto" {
+        return name.parse();
+    }
+    let default_format = FORMATS[0];
+    // detected prefix may not match log time format given
+    if args.is_present("prefix") || args.is_present("log-time-format") {
+        return Ok(default_format);
+    }
+    // stdin can not be read twice
+    let file = match files.iter().find(|&&file| file != STDIO) {
+        Some(file) => file,
+        None => return Ok(default_format),
+    };
+    // file may not be readable yet, like a followed file, it is reported when read later
+    let detections = formats::detect(file, DETECT_LINES, zstd_dictionary.map(Vec::as_slice))
+        .unwrap_or_else(|e| {
+            warn!("failed to detect format, {}", e);
+            Vec::new()
+        });
+    match detections.first() {
+        Some(detection) => {
+            info!(
+                "detect format {} from {}, prefix: {}, log time: {}",
+                detection.format.name, file, detection.format.pattern, detection.format.time_format
+            );
+            Ok(detection.format)
+        }
+        None => {
+            warn!("no format detected from {}, use default format", file);
+            Ok(default_format)
+        }
+    }
+}
+
+fn print_detections(file: &str, lines: usize) -> Result<()> {
+    let detections = formats::detect(file, lines, None)?;
+    println!("{}", file);
+    let best = match detections.first() {
+        Some(best) => best,
+        None => {
+            println!("  no format detected");
+            return Ok(());
+        }
+    };
+    println!(
+        "  format: {}\n  prefix: {}\n  log time: {}",
+        best.format.name, best.format.pattern, best.format.time_format
+    );
+    if let Some(time_field) = best.format.time_field {
+        println!("  time field: {}", time_field);
+    }
+    println!(
+        "  match rate: {:.2}% ({}/{} lines)",
+        best.rate() * 100.0,
+        best.matched,
+        best.total
+    );
+    if detections.len() > 1 {
+        println!("  other candidates:");
+        for detection in detections.iter().skip(1).take(5) {
+            println!(
+                "  {:>7.2}% {} {}",
+                detection.rate() * 100.0,
+                detection.format.name,
+                detection.format.pattern
+            );
+        }
+    }
+    Ok(())
+}
+
+/// prefix pattern of format unless given
+fn prefix(args: &ArgMatches, format: &LogFormat) -> String {
+    args.value_of("prefix")
+        .unwrap_or(format.pattern)
+        .to_string()
+}
+
+/// log time format of format unless given
+fn log_time_format(args: &ArgMatches, format: &LogFormat) -> String {
+    args.value_of("log-time-format")
+        .unwrap_or(format.time_format)
+        .to_string()
+}
+
+fn json_fields(args: &ArgMatches, format: &LogFormat) -> JsonFields {
+    JsonFields {
+        time: args
+            .value_of("time-field")
+            .or(format.time_field)
+            .map(|s| s.to_string()),
+        trace: args.value_of("trace-field").map(|s| s.to_string()),
+        level: args
+            .value_of("level-field")
+            .or(format.level_field)
+            .map(|s| s.to_string()),
+    }
+}
+
+/// dictionary for zstd read from file
+fn zstd_dictionary(args: &ArgMatches) -> Result<Option<Arc<Vec<u8>>>> {
+    args.value_of("zstd-dict")
+        .map(|file| {
+            fs::read(file)
+                .map(Arc::new)
+                .map_err(|e| LogyError::io(file, e))
+        })
+        .transpose()
+}
+
+fn dedup_options(args: &ArgMatches) -> Result<Option<DedupOptions>> {
+    let key = match args.value_of("dedup") {
+        Some("entry") => DedupKey::Entry,
+        Some("entry+source") => DedupKey::EntrySource,
+        Some(_) => DedupKey::Capture(args.value_of("dedup-pattern").unwrap().to_string()),
+        None => return Ok(None),
+    };
+    Ok(Some(DedupOptions {
+        key,
+        window: optional_arg(args, "dedup-window", parse_duration)?.unwrap_or_default(),
+    }))
+}
+
+fn time_zones(args: &ArgMatches) -> Result<TimeZones> {
+    let values = args
+        .values_of("tz")
+        .map(|values| values.collect::<Vec<&str>>())
+        .unwrap_or_default();
+    TimeZones::parse(&values)
+}
+
+/// time range with absolute times in default time zone
+fn time_range(args: &ArgMatches, zones: &TimeZones) -> Result<TimeRange> {
+    let zone = zones.default_zone();
+    Ok(TimeRange {
+        since: optional_arg(args, "since", |value| parse_time(value, &zone))?,
+        until: optional_arg(args, "until", |value| parse_time(value, &zone))?,
+        sorted: args.is_present("sorted"),
+    })
+}
+
+/// time period of output file, which must be positive
+fn parse_bucket(value: &str) -> result::Result<i64, String> {
+    match parse_duration(value)? {
+        bucket if bucket > 0 => Ok(bucket),
+        _ => Err(format!("bucket must be positive {}", value)),
+    }
+}
+
+/// parse size with optional unit K, M or G
+fn parse_size(value: &str) -> result::Result<u64, String> {
+    let (number, unit) = match value.to_ascii_uppercase().chars().last() {
+        Some('K') => (&value[..value.len() - 1], 1024),
+        Some('M') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('G') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|size| size.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size {}", value))
+}
+
+/// parse megabytes to bytes
+fn parse_megabytes(value: &str) -> result::Result<usize, String> {
+    value
+        .parse::<usize>()
+        .ok()
+        .and_then(|megabytes| megabytes.checked_mul(1024 * 1024))
+        .ok_or_else(|| format!("invalid megabytes {}", value))
+}
+
 fn command_args<'a, 'b>() -> App<'a, 'b> {
     App::new("logy")
         .version("0.0.1")
@@ -69,30 +440,175 @@ fn command_args<'a, 'b>() -> App<'a, 'b> {
                         .short("p")
                         .long("prefix")
                         .takes_value(true)
-                        .help("Prefix pattern to determin start of log line, includes log time and need be quoted")
-                        .default_value(r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#),
+                        .help("Prefix pattern to determin start of log line, includes log time and need be quoted, overrides prefix of format"),
                     Arg::with_name("log-time-format")
                         .short("t")
                         .long("log-time")
                         .takes_value(true)
-                        .help("Log time format to parse")
-                        .default_value("%Y-%m-%d %H:%M:%S%.3f"),
+                        .help("Log time format to parse, overrides log time format of format"),
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&format_names())
+                        .possible_value("auto")
+                        .help("Preset of prefix pattern and log time format, auto to detect from first file when prefix and log time are not given")
+                        .default_value("default"),
                     Arg::with_name("out-file-pattern")
                         .short("o")
                         .long("out-files")
                         .takes_value(true)
-                        .help("Output file pattern")
+                        .help("Output file pattern, - for stdout, may include {name} placeholders of --partition-by")
                         .default_value("output.%Y%m%d-%H.log"),
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .takes_value(true)
+                        .help("Files read and parsed in parallel, cores of machine by default"),
+                    Arg::with_name("sort")
+                        .long("sort")
+                        .conflicts_with("sorted")
+                        .help("Sort logs of files not in time order, sorted chunks over --sort-memory are spilled to temp files"),
+                    Arg::with_name("sort-memory")
+                        .long("sort-memory")
+                        .takes_value(true)
+                        .help("Memory for logs to sort, e.g. 512M")
+                        .default_value("256M"),
+                    Arg::with_name("dedup")
+                        .long("dedup")
+                        .takes_value(true)
+                        .possible_values(&["entry", "entry+source", "capture"])
+                        .help("Remove duplicate logs by whole log, whole log of same source file, or capture of --dedup-pattern"),
+                    Arg::with_name("dedup-pattern")
+                        .long("dedup-pattern")
+                        .takes_value(true)
+                        .required_if("dedup", "capture")
+                        .help("Pattern to capture key of logs by group key or the first group, like a message ID"),
+                    Arg::with_name("dedup-window")
+                        .long("dedup-window")
+                        .takes_value(true)
+                        .help("Time window of log time for duplicates, e.g. 500ms or 1m")
+                        .default_value("1s"),
+                    Arg::with_name("tag-source")
+                        .long("tag-source")
+                        .takes_value(true)
+                        .help("Tag logs with source files after log time, or as source field of JSON logs (_source when taken), e.g. \"[{basename}] \", {file} for full path"),
+                    Arg::with_name("source-pattern")
+                        .long("source-pattern")
+                        .takes_value(true)
+                        .requires("tag-source")
+                        .help("Pattern of file path with named captures to use as {name} placeholders of --tag-source"),
+                    Arg::with_name("tz")
+                        .long("tz")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Time zone of log times without offset, e.g. Asia/Taipei, or FILE=ZONE for a file, UTC by default"),
+                    Arg::with_name("out-tz")
+                        .long("out-tz")
+                        .takes_value(true)
+                        .help("Show log times in the time zone, output files are split by local time of it"),
+                    Arg::with_name("out-time")
+                        .long("out-time")
+                        .takes_value(true)
+                        .possible_values(&["rewrite", "annotate"])
+                        .help("Replace log time in place, or add it before log, JSON logs get time field replaced or local_time field added")
+                        .default_value("rewrite"),
+                    Arg::with_name("bucket")
+                        .long("bucket")
+                        .takes_value(true)
+                        .help("Time period per output file, e.g. 15m, 1d or 1w, finest time of output file pattern by default"),
                     Arg::with_name("compress-level")
                         .short("c")
                         .long("compress")
                         .takes_value(true)
                         .help("Compress level for output files")
                         .default_value("9"),
+                    Arg::with_name("codec")
+                        .long("codec")
+                        .takes_value(true)
+                        .possible_values(&["gzip", "zstd", "bzip2", "xz", "none"])
+                        .help("Compression format of output files, levels are 1-9 for gzip, bzip2 and xz, 1-22 for zstd")
+                        .default_value("gzip"),
+                    Arg::with_name("zstd-dict")
+                        .long("zstd-dict")
+                        .takes_value(true)
+                        .help("Dictionary from train-dict to read and write zstd files, output files are decoded with zstd -D"),
+                    Arg::with_name("compress-threads")
+                        .long("compress-threads")
+                        .takes_value(true)
+                        .help("Threads to compress blocks of output files in parallel as multi-member gzip, one gzip stream by default"),
+                    Arg::with_name("max-size")
+                        .long("max-size")
+                        .takes_value(true)
+                        .help("Roll to next numbered file when output file reaches the size, e.g. 100M"),
+                    Arg::with_name("max-lines")
+                        .long("max-lines")
+                        .takes_value(true)
+                        .help("Roll to next numbered file when output file reaches the line count"),
+                    Arg::with_name("retain")
+                        .long("retain")
+                        .takes_value(true)
+                        .help("Keep only the latest count of rolled files"),
+                    Arg::with_name("partition-by")
+                        .long("partition-by")
+                        .takes_value(true)
+                        .help("Pattern with named captures to use as {name} placeholders in output file pattern, prefix pattern by default"),
+                    Arg::with_name("max-open-files")
+                        .long("max-open-files")
+                        .takes_value(true)
+                        .help("Maximum count of output files kept open, least recently used ones are closed")
+                        .default_value("64"),
+                    Arg::with_name("since")
+                        .long("since")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .help("Skip logs before the time, e.g. \"2021-09-27 01:00:00\" or -2h"),
+                    Arg::with_name("until")
+                        .long("until")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .help("Skip logs from the time, e.g. \"2021-09-27 01:10:00\" or -1h"),
+                    Arg::with_name("sorted")
+                        .long("sorted")
+                        .help("Logs of each file are in time order, stop reading once --until is passed"),
+                    Arg::with_name("grep")
+                        .long("grep")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Keep logs matching any of the patterns, applied to all lines of log"),
+                    Arg::with_name("grep-v")
+                        .long("grep-v")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Drop logs matching any of the patterns, applied to all lines of log"),
+                    Arg::with_name("level")
+                        .long("level")
+                        .takes_value(true)
+                        .help("Keep logs of the level or higher, e.g. WARN, logs without level are dropped"),
+                    Arg::with_name("level-pattern")
+                        .long("level-pattern")
+                        .takes_value(true)
+                        .help("Pattern to capture level from first line of log, first capture group is the level"),
+                    Arg::with_name("time-field")
+                        .long("time-field")
+                        .takes_value(true)
+                        .help("Field of log time in JSON logs, epoch milliseconds, epoch seconds or RFC 3339, enables JSON input"),
+                    Arg::with_name("level-field")
+                        .long("level-field")
+                        .takes_value(true)
+                        .requires("time-field")
+                        .help("Field of level in JSON logs"),
+                    Arg::with_name("on-error")
+                        .long("on-error")
+                        .takes_value(true)
+                        .possible_values(&["skip", "warn", "abort"])
+                        .help("Policy for malformed logs")
+                        .default_value("warn"),
                     Arg::with_name("files")
                         .required(true)
                         .multiple(true)
-                        .help("Target files for reduce"),
+                        .help("Target files for reduce, - for stdin"),
                 ]),
         )
         .subcommand(
@@ -103,25 +619,65 @@ fn command_args<'a, 'b>() -> App<'a, 'b> {
                         .short("p")
                         .long("prefix")
                         .takes_value(true)
-                        .help("Prefix pattern to determin start of log line, includes log time and need be quoted")
-                        .default_value(r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#),
+                        .help("Prefix pattern to determin start of log line, includes log time and need be quoted, overrides prefix of format"),
                     Arg::with_name("log-time-format")
                         .short("t")
                         .long("log-time")
                         .takes_value(true)
-                        .help("Log time format to parse")
-                        .default_value("%Y-%m-%d %H:%M:%S%.3f"),
+                        .help("Log time format to parse, overrides log time format of format"),
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&format_names())
+                        .possible_value("auto")
+                        .help("Preset of prefix pattern and log time format, auto to detect from first file when prefix and log time are not given")
+                        .default_value("default"),
                     Arg::with_name("out-file-pattern")
                         .short("o")
                         .long("out-files")
                         .takes_value(true)
-                        .help("Output file pattern")
+                        .help("Output file pattern, - for stdout")
                         .default_value("traced.output.log"),
+                    Arg::with_name("compress-level")
+                        .short("c")
+                        .long("compress")
+                        .takes_value(true)
+                        .help("Compress level for output files, 0 for plain text")
+                        .default_value("0"),
+                    Arg::with_name("codec")
+                        .long("codec")
+                        .takes_value(true)
+                        .possible_values(&["gzip", "zstd", "bzip2", "xz", "none"])
+                        .help("Compression format of output files, levels are 1-9 for gzip, bzip2 and xz, 1-22 for zstd")
+                        .default_value("gzip"),
+                    Arg::with_name("zstd-dict")
+                        .long("zstd-dict")
+                        .takes_value(true)
+                        .help("Dictionary from train-dict to read and write zstd files, output files are decoded with zstd -D"),
+                    Arg::with_name("tag-source")
+                        .long("tag-source")
+                        .takes_value(true)
+                        .help("Tag logs with source files after log time, or as source field of JSON logs (_source when taken), e.g. \"[{basename}] \", {file} for full path"),
+                    Arg::with_name("source-pattern")
+                        .long("source-pattern")
+                        .takes_value(true)
+                        .requires("tag-source")
+                        .help("Pattern of file path with named captures to use as {name} placeholders of --tag-source"),
+                    Arg::with_name("tz")
+                        .long("tz")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Time zone of log times without offset, e.g. Asia/Taipei, or FILE=ZONE for a file, UTC by default"),
+                    Arg::with_name("bucket")
+                        .long("bucket")
+                        .takes_value(true)
+                        .help("Time period per output file, e.g. 15m, 1d or 1w, finest time of output file pattern by default"),
                     Arg::with_name("trace-pattern")
                         .short("g")
                         .long("trace-pattern")
                         .takes_value(true)
-                        .required(true)
+                        .required_unless("trace-field")
                         .help("Trace ID pattern in logs to group same process"),
                     Arg::with_name("minimal-cost-time")
                         .short("d")
@@ -129,10 +685,195 @@ fn command_args<'a, 'b>() -> App<'a, 'b> {
                         .takes_value(true)
                         .help("Minimal duration of traced process in milliseconds")
                         .default_value("8000"),
+                    Arg::with_name("idle-timeout")
+                        .long("idle-timeout")
+                        .takes_value(true)
+                        .help("Close a trace when no log of it comes within milliseconds")
+                        .default_value("300000"),
+                    Arg::with_name("max-memory")
+                        .long("max-memory")
+                        .takes_value(true)
+                        .help("Maximal megabytes of buffered trace logs, exceeded logs are spilled to disk")
+                        .default_value("512"),
+                    Arg::with_name("merge")
+                        .short("m")
+                        .long("merge")
+                        .help("Merge all files into one timeline by log time before tracing"),
+                    Arg::with_name("report")
+                        .short("r")
+                        .long("report")
+                        .takes_value(true)
+                        .possible_values(&["text", "json", "csv"])
+                        .help("Output format of long traces, json and csv output summary of traces")
+                        .default_value("text"),
+                    Arg::with_name("stats")
+                        .long("stats")
+                        .help("Print latency statistics and histogram of all traces"),
+                    Arg::with_name("stats-hourly")
+                        .long("stats-hourly")
+                        .help("Print latency statistics of all traces for each hour as well"),
+                    Arg::with_name("start-pattern")
+                        .long("start-pattern")
+                        .takes_value(true)
+                        .help("Pattern of log starts a traced process, duration is measured from it"),
+                    Arg::with_name("end-pattern")
+                        .long("end-pattern")
+                        .takes_value(true)
+                        .help("Pattern of log ends a traced process, traces with a start mark but without it are reported as unfinished"),
+                    Arg::with_name("since")
+                        .long("since")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .help("Skip logs before the time, e.g. \"2021-09-27 01:00:00\" or -2h"),
+                    Arg::with_name("until")
+                        .long("until")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .help("Skip logs from the time, e.g. \"2021-09-27 01:10:00\" or -1h"),
+                    Arg::with_name("sorted")
+                        .long("sorted")
+                        .help("Logs of each file are in time order, stop reading once --until is passed"),
+                    Arg::with_name("time-field")
+                        .long("time-field")
+                        .takes_value(true)
+                        .help("Field of log time in JSON logs, epoch milliseconds, epoch seconds or RFC 3339, enables JSON input"),
+                    Arg::with_name("trace-field")
+                        .long("trace-field")
+                        .takes_value(true)
+                        .requires("time-field")
+                        .help("Field of trace ID in JSON logs"),
+                    Arg::with_name("on-error")
+                        .long("on-error")
+                        .takes_value(true)
+                        .possible_values(&["skip", "warn", "abort"])
+                        .help("Policy for malformed logs")
+                        .default_value("warn"),
+                    Arg::with_name("files")
+                        .required(true)
+                        .multiple(true)
+                        .help("Target files for trace, - for stdin"),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("train-dict")
+                .about("Train zstd dictionary from logs, to compress small output files with --zstd-dict")
+                .args(&[
+                    Arg::with_name("prefix")
+                        .short("p")
+                        .long("prefix")
+                        .takes_value(true)
+                        .help("Prefix pattern to determin start of log line, overrides prefix of format"),
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&format_names())
+                        .possible_value("auto")
+                        .help("Preset of prefix pattern, auto to detect from first file when prefix is not given")
+                        .default_value("default"),
+                    Arg::with_name("out-file")
+                        .short("o")
+                        .long("out-file")
+                        .takes_value(true)
+                        .help("Output dictionary file")
+                        .default_value("logs.dict"),
+                    Arg::with_name("max-size")
+                        .long("max-size")
+                        .takes_value(true)
+                        .help("Maximum size of dictionary, e.g. 112K")
+                        .default_value("112K"),
+                    Arg::with_name("files")
+                        .required(true)
+                        .multiple(true)
+                        .help("Sample files of logs, - for stdin"),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("detect")
+                .about("Detect prefix pattern and log time format of log files")
+                .args(&[
+                    Arg::with_name("lines")
+                        .short("n")
+                        .long("lines")
+                        .takes_value(true)
+                        .help("Count of lines to sample from the beginning of file")
+                        .default_value("1000"),
+                    Arg::with_name("files")
+                        .required(true)
+                        .multiple(true)
+                        .help("Target files to detect, - for stdin"),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("tail")
+                .about("Merge new logs of growing files by log time")
+                .args(&[
+                    Arg::with_name("prefix")
+                        .short("p")
+                        .long("prefix")
+                        .takes_value(true)
+                        .help("Prefix pattern to determin start of log line, includes log time and need be quoted, overrides prefix of format"),
+                    Arg::with_name("log-time-format")
+                        .short("t")
+                        .long("log-time")
+                        .takes_value(true)
+                        .help("Log time format to parse, overrides log time format of format"),
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&format_names())
+                        .possible_value("auto")
+                        .help("Preset of prefix pattern and log time format, auto to detect from first file when prefix and log time are not given")
+                        .default_value("default"),
+                    Arg::with_name("out-file-pattern")
+                        .short("o")
+                        .long("out-files")
+                        .takes_value(true)
+                        .help("Output file pattern, - for stdout")
+                        .default_value("-"),
+                    Arg::with_name("tz")
+                        .long("tz")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Time zone of log times without offset, e.g. Asia/Taipei, or FILE=ZONE for a file, UTC by default"),
+                    Arg::with_name("bucket")
+                        .long("bucket")
+                        .takes_value(true)
+                        .help("Time period per output file, e.g. 15m, 1d or 1w, finest time of output file pattern by default"),
+                    Arg::with_name("follow")
+                        .short("f")
+                        .long("follow")
+                        .help("Keep watching files for new logs, rotated files are reopened"),
+                    Arg::with_name("from-beginning")
+                        .short("b")
+                        .long("from-beginning")
+                        .help("Read existing logs instead of starting from end of files"),
+                    Arg::with_name("reorder-window")
+                        .short("w")
+                        .long("reorder-window")
+                        .takes_value(true)
+                        .help("Milliseconds of log time to hold logs for merging in order")
+                        .default_value("1000"),
+                    Arg::with_name("release-timeout")
+                        .long("release-timeout")
+                        .takes_value(true)
+                        .help("Milliseconds to wait for new logs before emitting all held logs")
+                        .default_value("1000"),
+                    Arg::with_name("flush-timeout")
+                        .long("flush-timeout")
+                        .takes_value(true)
+                        .help("Milliseconds to wait for more lines of last log before emitting it")
+                        .default_value("500"),
+                    Arg::with_name("on-error")
+                        .long("on-error")
+                        .takes_value(true)
+                        .possible_values(&["skip", "warn", "abort"])
+                        .help("Policy for malformed logs")
+                        .default_value("warn"),
                     Arg::with_name("files")
                         .required(true)
                         .multiple(true)
-                        .help("Target files for trace"),
+                        .help("Target files to follow, stdin is not supported"),
                 ]),
         )
 }
@@ -1,14 +1,37 @@
-use chrono::{Duration, NaiveDateTime};
-use flate2::{bufread::GzDecoder, write::GzEncoder, Compression};
-use log::info;
-use regex::Regex;
+use bzip2::{bufread::MultiBzDecoder, write::BzEncoder};
+use chrono::{
+    offset::LocalResult, DateTime, Datelike, Local, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use flate2::{bufread::MultiGzDecoder, write::GzEncoder, Compression};
+use log::{debug, info};
+use regex::{Captures, Match, Regex};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
-    cmp::{self, min},
+    borrow::Cow,
+    cmp::min,
+    collections::{hash_map::Entry, HashMap, VecDeque},
     fmt::Display,
     fs::{self, File, OpenOptions},
-    io::{BufRead, BufReader, BufWriter, Write},
+    io::{self, BufRead, BufReader, BufWriter, Write},
     path::Path,
+    result,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
 };
+use xz2::{bufread::XzDecoder, write::XzEncoder};
+use zstd::stream::{read::Decoder as ZstdDecoder, write::Encoder as ZstdEncoder};
+
+use super::errors::{LogyError, Result};
+
+/// File name stands for stdin as input or stdout as output
+pub(crate) const STDIO: &str = "-";
 
 #[derive(Debug)]
 pub(crate) struct LogDuration {
@@ -17,77 +40,340 @@ pub(crate) struct LogDuration {
     pub end_time: i64,
 }
 
+/// Options of output files
+#[derive(Debug, Clone, Default)]
+pub struct WriterOptions {
+    // level of codec, output file is not compressed when 0
+    pub compress_level: u32,
+    // compression format of output files, gzip by default
+    pub codec: Codec,
+    // dictionary for zstd, trained from similar logs to compress small files better
+    pub zstd_dictionary: Option<Arc<Vec<u8>>>,
+    // roll to next numbered file when bytes of file reach the size
+    pub max_size: Option<u64>,
+    // roll to next numbered file when lines of file reach the count
+    pub max_lines: Option<u64>,
+    // keep only the latest rolled files, older ones are removed
+    pub retention: Option<usize>,
+    // milliseconds of time period per output file, finest time specifier of pattern by default
+    pub bucket: Option<i64>,
+    // named captures of logs used as {name} placeholders in file name pattern
+    pub partition_by: Option<String>,
+    // files kept open at the same time, least recently used ones are closed
+    pub max_open_files: Option<usize>,
+    // file names and time buckets are of local time in this time zone, UTC by default
+    pub time_zone: Option<Tz>,
+    // blocks of output compressed in parallel, one gzip stream when not more than 1
+    pub compress_threads: Option<usize>,
+    // threads compressing blocks, shared by all writers created with these options
+    pub compress_pool: Option<Arc<CompressPool>>,
+}
+
+impl WriterOptions {
+    pub fn new(compress_level: u32) -> WriterOptions {
+        WriterOptions {
+            compress_level,
+            ..Default::default()
+        }
+    }
+
+    /// start compress pool for compress threads, writers cloned from result share the pool
+    pub fn with_compress_pool(mut self) -> WriterOptions {
+        let threads = self.compress_threads.unwrap_or(1);
+        if self.compress_pool.is_none() && threads > 1 && self.output_codec() == Codec::Gzip {
+            self.compress_pool = Some(Arc::new(CompressPool::new(threads)));
+        }
+        self
+    }
+
+    fn rolling(&self) -> bool {
+        self.max_size.is_some() || self.max_lines.is_some()
+    }
+
+    /// codec of output files, none when compress level is 0
+    pub fn output_codec(&self) -> Codec {
+        if self.compress_level == 0 {
+            Codec::None
+        } else {
+            self.codec
+        }
+    }
+}
+
 pub(crate) struct WrappedFileWriter {
-    // controls output file is compressed, value is from 0 to 9
-    compress_level: u32,
+    options: WriterOptions,
     // last output file name
     filename: String,
     // outout file name pattern
     pattern: String,
     // flag for written content, true when not write operation occurs
     empty_content: bool,
+    // first line of each output file
+    header: Option<String>,
+    // flag for header not written to current file yet
+    header_pending: bool,
     // output stream
     writer: Box<dyn Write>,
+    // file handle released, reopened for appending on next write
+    closed: bool,
+    // milliseconds of time period per output file
+    bucket: i64,
+    // start time of current time period
+    bucket_start: i64,
+    // number of rolled file in current time, starts from 1
+    index: u32,
+    // lines written to current file
+    written_lines: u64,
+    // bytes written to current file
+    written_bytes: Arc<AtomicU64>,
+    // files completed, oldest first
+    rolled_files: VecDeque<String>,
 }
 
 impl WrappedFileWriter {
-    pub fn new(filename_pattern: &str, compress_level: u32) -> WrappedFileWriter {
+    pub fn new(filename_pattern: &str, mut options: WriterOptions) -> Result<WrappedFileWriter> {
+        // stdout is always plain text and never rolled
+        if filename_pattern == STDIO {
+            options = WriterOptions::default();
+        }
+        let options = options.with_compress_pool();
+        let index = if options.rolling() { 1 } else { 0 };
         let (file, appendable) =
-            WrappedFileWriter::as_filename(filename_pattern, 0, compress_level);
+            WrappedFileWriter::as_filename(filename_pattern, 0, &options, index);
         let filename = file.as_str();
 
         info!("create file {}", filename);
 
-        WrappedFileWriter {
-            compress_level,
+        let written_bytes = Arc::new(AtomicU64::new(0));
+        let bucket = options
+            .bucket
+            .unwrap_or_else(|| finest_bucket(filename_pattern));
+        Ok(WrappedFileWriter {
+            writer: WrappedFileWriter::create_writer(
+                filename,
+                appendable,
+                &options,
+                written_bytes.clone(),
+            )?,
+            options,
             filename: filename.to_string(),
             pattern: filename_pattern.to_string(),
             empty_content: true,
-            writer: WrappedFileWriter::create_writer(filename, appendable, compress_level),
+            header: None,
+            header_pending: false,
+            closed: false,
+            bucket,
+            bucket_start: 0,
+            index,
+            written_lines: 0,
+            written_bytes,
+            rolled_files: VecDeque::new(),
+        })
+    }
+
+    /// write header at the beginning of every new output file
+    pub fn with_header(mut self, header: &str) -> WrappedFileWriter {
+        // appended file may have header already, stdout always needs one
+        self.header_pending = self.filename == STDIO
+            || Path::new(&self.filename)
+                .metadata()
+                .map(|metadata| metadata.len() == 0)
+                .unwrap_or(true);
+        self.header = Some(header.to_string());
+        self
+    }
+
+    /// write log to file of the time bucket which log time belongs to, log time is in epoch
+    /// milliseconds
+    pub fn write(&mut self, log_time: i64, line: &str) -> Result<()> {
+        self.prepare(log_time)?;
+        writeln!(self.writer, "{}", line).map_err(|e| LogyError::io(&self.filename, e))?;
+        self.empty_content = false;
+        self.written_lines += 1;
+        Ok(())
+    }
+
+    /// write lines as one log in the same file, lines are taken one by one so they need not be
+    /// kept in memory together
+    pub fn write_lines(
+        &mut self,
+        log_time: i64,
+        lines: impl Iterator<Item = Result<String>>,
+    ) -> Result<()> {
+        self.prepare(log_time)?;
+        for line in lines {
+            writeln!(self.writer, "{}", line?).map_err(|e| LogyError::io(&self.filename, e))?;
         }
+        self.empty_content = false;
+        self.written_lines += 1;
+        Ok(())
     }
 
-    pub fn write(&mut self, log_hour: i64, line: &str) {
-        let (filename, appendable) =
-            WrappedFileWriter::as_filename(self.pattern.as_str(), log_hour, self.compress_level);
+    /// roll to file of log time and write header when needed
+    fn prepare(&mut self, log_time: i64) -> Result<()> {
+        let log_time = match &self.options.time_zone {
+            Some(zone) => instant_to_local(log_time, zone),
+            None => log_time,
+        };
+        let mut filename = self.filename.clone();
+        let mut appendable = false;
+        let bucket_start = start_of_bucket(log_time, self.bucket);
+        if bucket_start != self.bucket_start {
+            self.bucket_start = bucket_start;
+            let (file, file_appendable) = WrappedFileWriter::as_filename(
+                &self.pattern,
+                bucket_start,
+                &self.options,
+                self.index,
+            );
+            if self.filename != file && self.options.rolling() {
+                // new time period, numbering from start
+                self.index = 1;
+                let (first_file, first_appendable) =
+                    WrappedFileWriter::as_filename(&self.pattern, bucket_start, &self.options, 1);
+                filename = first_file;
+                appendable = first_appendable;
+            } else {
+                filename = file;
+                appendable = file_appendable;
+            }
+        }
+        if self.filename == filename && self.reach_limit() {
+            self.index += 1;
+            let (next_file, next_appendable) = WrappedFileWriter::as_filename(
+                &self.pattern,
+                self.bucket_start,
+                &self.options,
+                self.index,
+            );
+            filename = next_file;
+            appendable = next_appendable;
+        }
+
         if self.filename != filename {
-            self.writer.flush().unwrap();
-
-            // check file size and remove zero size file
-            let previous_file = self.filename.as_str();
-            let previous_path = Path::new(previous_file);
-            if previous_path.exists()
-                && (previous_path.metadata().unwrap().len() == 0
-                    || (self.compress_level > 0 && self.empty_content))
+            self.roll(filename, appendable)?;
+        } else if self.closed {
+            debug!("reopen file {}", self.filename);
+            self.writer = WrappedFileWriter::create_writer(
+                &self.filename,
+                true,
+                &self.options,
+                self.written_bytes.clone(),
+            )?;
+            self.closed = false;
+        }
+        if self.header_pending {
+            if let Some(header) = &self.header {
+                writeln!(self.writer, "{}", header)
+                    .map_err(|e| LogyError::io(&self.filename, e))?;
+            }
+            self.header_pending = false;
+        }
+        Ok(())
+    }
+
+    fn reach_limit(&self) -> bool {
+        self.options
+            .max_lines
+            .is_some_and(|max_lines| self.written_lines >= max_lines)
+            || self
+                .options
+                .max_size
+                .is_some_and(|max_size| self.written_bytes.load(Ordering::Relaxed) >= max_size)
+    }
+
+    /// close current file and continue with the new one
+    fn roll(&mut self, filename: String, appendable: bool) -> Result<()> {
+        self.flush()?;
+        // finish compressed stream before checking file size
+        self.writer = Box::new(io::sink());
+
+        // check file size and remove zero size file
+        let previous_file = self.filename.clone();
+        let previous_path = Path::new(&previous_file);
+        if previous_path.exists() {
+            if previous_path
+                .metadata()
+                .map_err(|e| LogyError::io(&previous_file, e))?
+                .len()
+                == 0
+                || (self.options.output_codec() != Codec::None && self.empty_content)
             {
                 info!("remove zero size file: {}", previous_file);
-                fs::remove_file(previous_file).unwrap();
+                fs::remove_file(&previous_file).map_err(|e| LogyError::io(&previous_file, e))?;
+            } else {
+                self.rolled_files.push_back(previous_file);
             }
+        }
 
-            info!("create file {}", filename);
-            self.filename = filename;
-            self.writer = WrappedFileWriter::create_writer(
-                self.filename.as_str(),
-                appendable,
-                self.compress_level,
-            )
+        if let Some(retention) = self.options.retention {
+            while self.rolled_files.len() > retention {
+                if let Some(expired_file) = self.rolled_files.pop_front() {
+                    info!("remove expired file: {}", expired_file);
+                    fs::remove_file(&expired_file).map_err(|e| LogyError::io(&expired_file, e))?;
+                }
+            }
         }
-        // self.writer.write_all(line.as_bytes()).unwrap();
-        writeln!(self.writer, "{}", line).unwrap();
-        self.empty_content = false
+
+        info!("create file {}", filename);
+        self.written_bytes = Arc::new(AtomicU64::new(0));
+        self.writer = WrappedFileWriter::create_writer(
+            &filename,
+            appendable,
+            &self.options,
+            self.written_bytes.clone(),
+        )?;
+        self.filename = filename;
+        self.closed = false;
+        self.empty_content = true;
+        self.header_pending = self.header.is_some();
+        self.written_lines = 0;
+        Ok(())
     }
 
-    fn as_filename(log_file_pattern: &str, log_hour: i64, compress_level: u32) -> (String, bool) {
-        let pattern = log_file_pattern.to_owned() + if compress_level > 0 { ".gz" } else { "" };
-        let file_time =
-            NaiveDateTime::from_timestamp(log_hour * Duration::hours(1).num_seconds(), 0);
-        let new_file = format!("{}", file_time.format(pattern.as_str()));
-        (new_file.clone(), new_file == pattern)
+    /// format file name with log time, number of rolled file is inserted before extension
+    fn as_filename(
+        log_file_pattern: &str,
+        log_time: i64,
+        options: &WriterOptions,
+        index: u32,
+    ) -> (String, bool) {
+        if log_file_pattern == STDIO {
+            return (STDIO.to_string(), true);
+        }
+        let file_time = NaiveDateTime::from_timestamp(
+            log_time.div_euclid(1000),
+            (log_time.rem_euclid(1000) * 1_000_000) as u32,
+        );
+        let mut new_file = format!("{}", file_time.format(log_file_pattern));
+        let appendable = new_file == log_file_pattern && index == 0;
+
+        if index > 0 {
+            let basename_start = new_file.rfind('/').map_or(0, |i| i + 1);
+            let number = format!(".{:03}", index);
+            match new_file[basename_start..].rfind('.') {
+                Some(dot) if dot > 0 => new_file.insert_str(basename_start + dot, &number),
+                _ => new_file.push_str(&number),
+            }
+        }
+        new_file.push_str(options.output_codec().extension());
+        (new_file, appendable)
     }
 
-    fn create_writer(filename: &str, appendable: bool, compress_level: u32) -> Box<dyn Write> {
-        if !Path::new(filename).parent().unwrap().exists() {
-            fs::create_dir_all(Path::new(filename).parent().unwrap()).unwrap();
+    fn create_writer(
+        filename: &str,
+        appendable: bool,
+        options: &WriterOptions,
+        written_bytes: Arc<AtomicU64>,
+    ) -> Result<Box<dyn Write>> {
+        if filename == STDIO {
+            return Ok(Box::new(BufWriter::new(io::stdout())));
+        }
+
+        if let Some(parent) = Path::new(filename).parent() {
+            if !parent.exists() {
+                fs::create_dir_all(parent).map_err(|e| LogyError::io(filename, e))?;
+            }
         }
 
         let file = OpenOptions::new()
@@ -96,45 +382,652 @@ impl WrappedFileWriter {
             .truncate(!appendable)
             .create(true)
             .open(filename)
-            .unwrap();
+            .map_err(|e| LogyError::io(filename, e))?;
+        // count bytes going to file, compressed data held by encoder is not counted yet
+        let file = CountingWriter {
+            inner: BufWriter::new(file),
+            count: written_bytes.clone(),
+        };
 
-        if compress_level > 0 {
-            Box::new(GzEncoder::new(
-                BufWriter::new(file),
-                Compression::new(min(9, compress_level)),
-            ))
-        } else {
-            Box::new(BufWriter::new(file))
+        let level = options.compress_level;
+        let encoder: Box<dyn Write> = match options.output_codec() {
+            Codec::None => Box::new(file),
+            Codec::Gzip => {
+                let level = Compression::new(min(9, level));
+                match &options.compress_pool {
+                    Some(pool) => Box::new(ParallelGzEncoder::new(
+                        file,
+                        level,
+                        pool.clone(),
+                        written_bytes,
+                    )),
+                    None => Box::new(GzEncoder::new(file, level)),
+                }
+            }
+            Codec::Zstd => {
+                let level = min(level, 22) as i32;
+                let encoder = match &options.zstd_dictionary {
+                    Some(dictionary) => ZstdEncoder::with_dictionary(file, level, dictionary),
+                    None => ZstdEncoder::new(file, level),
+                }
+                .map_err(|e| LogyError::io(filename, e))?;
+                // zstd stream is not finished on drop otherwise
+                Box::new(encoder.auto_finish())
+            }
+            Codec::Bzip2 => Box::new(BzEncoder::new(file, bzip2::Compression::new(min(9, level)))),
+            Codec::Xz => Box::new(XzEncoder::new(file, min(9, level))),
+        };
+        Ok(encoder)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .map_err(|e| LogyError::io(&self.filename, e))
+    }
+
+    /// release file handle, file is reopened when more logs come
+    pub fn close(&mut self) -> Result<()> {
+        self.flush()?;
+        // finish compressed stream
+        self.writer = Box::new(io::sink());
+        self.closed = true;
+        Ok(())
+    }
+
+    /// close file and keep only the state to reopen it with `resume`
+    pub fn into_state(mut self) -> Result<WriterState> {
+        self.close()?;
+        Ok(WriterState {
+            filename: self.filename,
+            empty_content: self.empty_content,
+            bucket_start: self.bucket_start,
+            index: self.index,
+            written_lines: self.written_lines,
+            written_bytes: self.written_bytes.load(Ordering::Relaxed),
+            rolled_files: self.rolled_files,
+        })
+    }
+
+    /// writer of closed file, file is reopened for appending on next write
+    pub fn resume(
+        filename_pattern: &str,
+        options: WriterOptions,
+        state: WriterState,
+    ) -> WrappedFileWriter {
+        WrappedFileWriter {
+            writer: Box::new(io::sink()),
+            bucket: options
+                .bucket
+                .unwrap_or_else(|| finest_bucket(filename_pattern)),
+            options,
+            filename: state.filename,
+            pattern: filename_pattern.to_string(),
+            empty_content: state.empty_content,
+            header: None,
+            header_pending: false,
+            closed: true,
+            bucket_start: state.bucket_start,
+            index: state.index,
+            written_lines: state.written_lines,
+            written_bytes: Arc::new(AtomicU64::new(state.written_bytes)),
+            rolled_files: state.rolled_files,
+        }
+    }
+}
+
+/// What a closed writer needs to continue its file, options are kept by owner of writers
+pub(crate) struct WriterState {
+    filename: String,
+    empty_content: bool,
+    bucket_start: i64,
+    index: u32,
+    written_lines: u64,
+    written_bytes: u64,
+    rolled_files: VecDeque<String>,
+}
+
+/// Writers of output files partitioned by named captures of logs
+pub(crate) struct WriterPool {
+    // file name pattern with {name} placeholders
+    pattern: String,
+    placeholder: Regex,
+    partition: Option<Regex>,
+    options: WriterOptions,
+    // writers of open files by resolved file name pattern, with last used sequence
+    writers: HashMap<String, (WrappedFileWriter, u64)>,
+    // closed writers by resolved file name pattern, reopened when their logs come again
+    closed: HashMap<String, WriterState>,
+    used_count: u64,
+}
+
+impl WriterPool {
+    pub fn new(filename_pattern: &str, options: WriterOptions) -> Result<WriterPool> {
+        let options = options.with_compress_pool();
+        let partition = match &options.partition_by {
+            Some(pattern) => Some(Regex::new(pattern).map_err(|e| LogyError::pattern(pattern, e))?),
+            None => None,
+        };
+        let placeholder = Regex::new(r"\{(\w+)\}").unwrap();
+        for captures in placeholder.captures_iter(filename_pattern) {
+            let name = &captures[1];
+            let captured = partition
+                .as_ref()
+                .is_some_and(|re| re.capture_names().flatten().any(|n| n == name));
+            if !captured {
+                return Err(LogyError::argument("partition-by", name));
+            }
+        }
+
+        // no need to capture logs when file name has no placeholder
+        let partition = partition.filter(|_| placeholder.is_match(filename_pattern));
+        Ok(WriterPool {
+            pattern: filename_pattern.to_string(),
+            placeholder,
+            partition,
+            options,
+            writers: HashMap::new(),
+            closed: HashMap::new(),
+            used_count: 0,
+        })
+    }
+
+    pub fn write(&mut self, log_time: i64, line: &str) -> Result<()> {
+        let key = self.resolve(line);
+        self.used_count += 1;
+
+        if !self.writers.contains_key(&key) {
+            self.release_handle()?;
+        }
+        let (writer, last_used) = match self.writers.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let writer = match self.closed.remove(entry.key()) {
+                    Some(state) => {
+                        WrappedFileWriter::resume(entry.key(), self.options.clone(), state)
+                    }
+                    None => WrappedFileWriter::new(entry.key(), self.options.clone())?,
+                };
+                entry.insert((writer, 0))
+            }
+        };
+        *last_used = self.used_count;
+        writer.write(log_time, line)
+    }
+
+    /// file name pattern of log, captured values replace placeholders
+    fn resolve(&self, line: &str) -> String {
+        let partition = match &self.partition {
+            Some(partition) => partition,
+            None => return self.pattern.clone(),
+        };
+        let captures = partition.captures(line);
+        let resolved = self
+            .placeholder
+            .replace_all(&self.pattern, |placeholder: &Captures| {
+                let value = captures
+                    .as_ref()
+                    .and_then(|captures| captures.name(&placeholder[1]))
+                    .map(|value| value.as_str())
+                    .filter(|value| !value.is_empty())
+                    .unwrap_or("unknown");
+                // captured value stays in one directory level and is not a time specifier
+                value.replace(['/', '\\'], "_").replace('%', "%%")
+            });
+        // captured values like `..` must not point to another directory, values have no separator
+        // so segments of pattern and resolved one are in pairs
+        resolved
+            .split('/')
+            .zip(self.pattern.split('/'))
+            .map(|(segment, pattern)| {
+                if (segment == "." || segment == "..") && self.placeholder.is_match(pattern) {
+                    segment.replace('.', "_")
+                } else {
+                    segment.to_string()
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("/")
+    }
+
+    /// close least recently used file when open files reach the limit
+    fn release_handle(&mut self) -> Result<()> {
+        let max_open_files = match self.options.max_open_files {
+            Some(max_open_files) => max_open_files.max(1),
+            None => return Ok(()),
+        };
+        if self.writers.len() < max_open_files {
+            return Ok(());
+        }
+        let least_used = self
+            .writers
+            .iter()
+            .min_by_key(|(_, (_, last_used))| *last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = least_used {
+            let (writer, _) = self.writers.remove(&key).unwrap();
+            self.closed.insert(key, writer.into_state()?);
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        for (writer, _) in self.writers.values_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts bytes written to inner writer
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: Arc<AtomicU64>,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.count.fetch_add(size as u64, Ordering::Relaxed);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// input bytes compressed as one gzip member by parallel encoder
+const GZIP_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Threads compressing blocks for parallel encoders of all output files
+///
+/// At most `threads` blocks wait in queue, so encoders are blocked instead of buffering more input
+/// than the pool can compress.
+#[derive(Debug)]
+pub struct CompressPool {
+    jobs: SyncSender<CompressJob>,
+    threads: usize,
+}
+
+struct CompressJob {
+    block: Vec<u8>,
+    level: Compression,
+    member: SyncSender<io::Result<Vec<u8>>>,
+}
+
+impl CompressPool {
+    pub fn new(threads: usize) -> CompressPool {
+        let (jobs, job_rx) = mpsc::sync_channel::<CompressJob>(threads);
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        for _ in 0..threads {
+            let job_rx = Arc::clone(&job_rx);
+            thread::spawn(move || loop {
+                let job = match job_rx.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    // pool dropped
+                    Err(_) => return,
+                };
+                // encoder may be dropped already, nothing to do
+                let _ = job.member.send(compress_member(&job.block, job.level));
+            });
+        }
+        CompressPool { jobs, threads }
+    }
+
+    /// queue block to compress, compressed member is received from returned receiver
+    fn compress(
+        &self,
+        block: Vec<u8>,
+        level: Compression,
+    ) -> io::Result<Receiver<io::Result<Vec<u8>>>> {
+        let (member, member_rx) = mpsc::sync_channel(1);
+        self.jobs
+            .send(CompressJob {
+                block,
+                level,
+                member,
+            })
+            .map_err(|_| io::Error::other("compress pool stopped"))?;
+        Ok(member_rx)
+    }
+}
+
+/// Compresses blocks of input with a shared pool, blocks are written in order as members of gzip
+///
+/// Concatenated members are decoded as a whole by gunzip and other gzip readers. Input not written
+/// to file yet is counted in `buffered`, so size of file is not underestimated.
+struct ParallelGzEncoder<W: Write> {
+    inner: W,
+    level: Compression,
+    pool: Arc<CompressPool>,
+    // block being filled
+    block: Vec<u8>,
+    // input size and receiver of blocks being compressed, in order of input
+    pending: VecDeque<(usize, Receiver<io::Result<Vec<u8>>>)>,
+    buffered: Arc<AtomicU64>,
+    // any member written to inner
+    written: bool,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    fn new(
+        inner: W,
+        level: Compression,
+        pool: Arc<CompressPool>,
+        buffered: Arc<AtomicU64>,
+    ) -> ParallelGzEncoder<W> {
+        ParallelGzEncoder {
+            inner,
+            level,
+            pool,
+            block: Vec::new(),
+            pending: VecDeque::new(),
+            buffered,
+            written: false,
+        }
+    }
+
+    /// queue the filled block to pool, write members which are compressed already
+    fn submit_block(&mut self) -> io::Result<()> {
+        if !self.block.is_empty() {
+            let block = std::mem::take(&mut self.block);
+            let size = block.len();
+            let member = self.pool.compress(block, self.level)?;
+            self.pending.push_back((size, member));
+        }
+        self.write_members(false)
+    }
+
+    /// write compressed members in order, waits for all of them when `all` is true, otherwise
+    /// only when too many blocks are pending
+    fn write_members(&mut self, all: bool) -> io::Result<()> {
+        while let Some((size, member_rx)) = self.pending.front() {
+            let member = if all || self.pending.len() > self.pool.threads {
+                member_rx
+                    .recv()
+                    .map_err(|_| io::Error::other("compress pool stopped"))?
+            } else {
+                match member_rx.try_recv() {
+                    Ok(member) => member,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        return Err(io::Error::other("compress pool stopped"))
+                    }
+                }
+            };
+            let size = *size;
+            self.pending.pop_front();
+            self.inner.write_all(&member?)?;
+            self.buffered.fetch_sub(size as u64, Ordering::Relaxed);
+            self.written = true;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.submit_block()?;
+        self.write_members(true)?;
+        if !self.written {
+            // an empty stream, as written by single encoder
+            self.inner.write_all(&compress_member(&[], self.level)?)?;
+            self.written = true;
+        }
+        self.inner.flush()
+    }
+}
+
+fn compress_member(block: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(block.len() / 2), level);
+    encoder.write_all(block)?;
+    encoder.finish()
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffered.fetch_add(buf.len() as u64, Ordering::Relaxed);
+        let mut written = 0;
+        while written < buf.len() {
+            if self.block.len() >= GZIP_BLOCK_SIZE {
+                self.submit_block()?;
+            }
+            if self.block.capacity() == 0 {
+                self.block.reserve_exact(GZIP_BLOCK_SIZE);
+            }
+            let size = min(GZIP_BLOCK_SIZE - self.block.len(), buf.len() - written);
+            self.block.extend_from_slice(&buf[written..written + size]);
+            written += size;
         }
+        Ok(buf.len())
+    }
+
+    /// compress buffered data as members, so data written so far can be read from file
+    fn flush(&mut self) -> io::Result<()> {
+        self.submit_block()?;
+        self.write_members(true)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for ParallelGzEncoder<W> {
+    fn drop(&mut self) {
+        // errors can not be reported on drop, same as single encoder
+        let _ = self.finish();
     }
+}
+
+const SECOND: i64 = 1000;
+const MINUTE: i64 = 60 * SECOND;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
 
-    pub fn flush(&mut self) {
-        self.writer.flush().unwrap();
+/// parse duration like 500ms, 30s, 15m, 2h, 1d or 1w into milliseconds
+pub(crate) fn parse_duration(value: &str) -> result::Result<i64, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number
+        .parse::<i64>()
+        .map_err(|_| format!("invalid duration {}", value))?;
+    let unit = match unit {
+        "ms" => 1,
+        "s" => SECOND,
+        "m" => MINUTE,
+        "h" => HOUR,
+        "d" => DAY,
+        "w" => WEEK,
+        _ => return Err(format!("invalid duration unit {}", value)),
+    };
+    number
+        .checked_mul(unit)
+        .ok_or_else(|| format!("duration out of range {}", value))
+}
+
+/// time period of the finest time specifier in file name pattern, a day at most
+fn finest_bucket(pattern: &str) -> i64 {
+    let mut bucket = DAY;
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            continue;
+        }
+        // skip padding, width and fraction modifiers
+        let specifier = chars
+            .by_ref()
+            .find(|c| !matches!(c, '-' | '_' | '0'..='9' | '.' | ':' | '#'));
+        let unit = match specifier {
+            Some('S' | 's' | 'T' | 'X' | 'r' | 'c' | 'f' | '+') => SECOND,
+            Some('M' | 'R') => MINUTE,
+            Some('H' | 'I' | 'k' | 'l' | 'P' | 'p') => HOUR,
+            _ => DAY,
+        };
+        bucket = bucket.min(unit);
     }
+    bucket
+}
+
+/// start time of period which the time belongs to, weeks start on Monday
+fn start_of_bucket(time: i64, bucket: i64) -> i64 {
+    // 1970-01-01 is Thursday, first Monday is 4 days later
+    let offset = if bucket % WEEK == 0 { 4 * DAY } else { 0 };
+    (time - offset).div_euclid(bucket) * bucket + offset
 }
 
 pub(crate) struct WrappedFileReader {
     file: String,
     pattern: Regex,
-    reader: Box<dyn BufRead>,
-    buffer: Box<Vec<String>>,
+    reader: Box<dyn BufRead + Send>,
+    buffer: Vec<String>,
+    // count of lines read from file
+    read_count: u64,
+    // line number of first line in buffer
+    buffer_line_number: u64,
+    // line number of first line of last returned log
+    line_number: u64,
+    // line of JSON object starts a new log as well
+    json_lines: bool,
+    // JSON object of first line in buffer
+    buffer_json: Option<Value>,
+    // JSON object of last returned log
+    json: Option<Value>,
 }
 
 impl WrappedFileReader {
-    pub fn new(file: &str, pattern: &str, compressed: bool) -> WrappedFileReader {
-        WrappedFileReader {
+    pub fn new(
+        file: &str,
+        pattern: &str,
+        zstd_dictionary: Option<&[u8]>,
+    ) -> Result<WrappedFileReader> {
+        Ok(WrappedFileReader {
             file: file.to_string(),
-            pattern: Regex::new(pattern).unwrap(),
-            reader: if compressed {
-                Box::new(BufReader::new(GzDecoder::new(BufReader::new(
-                    File::open(file).unwrap(),
-                ))))
-            } else {
-                Box::new(BufReader::new(File::open(file).unwrap()))
-            },
-            buffer: Box::new(Vec::new()),
+            pattern: Regex::new(pattern).map_err(|e| LogyError::pattern(pattern, e))?,
+            reader: open_source(file, zstd_dictionary)?,
+            buffer: Vec::new(),
+            read_count: 0,
+            buffer_line_number: 0,
+            line_number: 0,
+            json_lines: false,
+            buffer_json: None,
+            json: None,
+        })
+    }
+
+    pub fn with_json_lines(mut self, json_lines: bool) -> WrappedFileReader {
+        self.json_lines = json_lines;
+        self
+    }
+
+    /// line number of first line of last returned log
+    pub fn line_number(&self) -> u64 {
+        self.line_number
+    }
+
+    /// JSON object of last returned log, parsed once when reading JSON lines
+    pub fn json(&self) -> Option<&Value> {
+        self.json.as_ref()
+    }
+
+    fn take_buffer(&mut self) -> Log {
+        let full_log = self.buffer.join("\n");
+        self.buffer.clear();
+        self.line_number = self.buffer_line_number;
+        self.json = self.buffer_json.take();
+        Log::Line(full_log)
+    }
+}
+
+/// open file or stdin, compressed file is decompressed by its format, zstd frames with dictionary
+/// need the dictionary they are compressed with
+pub(crate) fn open_source(
+    file: &str,
+    zstd_dictionary: Option<&[u8]>,
+) -> Result<Box<dyn BufRead + Send>> {
+    let mut source: Box<dyn BufRead + Send> = if file == STDIO {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(
+            File::open(file).map_err(|e| LogyError::io(file, e))?,
+        ))
+    };
+    let codec = Codec::detect(source.fill_buf().map_err(|e| LogyError::io(file, e))?);
+    debug!("open file {} as {:?}", file, codec);
+    codec
+        .decoder(source, zstd_dictionary)
+        .map_err(|e| LogyError::io(file, e))
+}
+
+/// Compression format of a log file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    None,
+    #[default]
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl FromStr for Codec {
+    type Err = LogyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Codec::None),
+            "gzip" => Ok(Codec::Gzip),
+            "zstd" => Ok(Codec::Zstd),
+            "bzip2" => Ok(Codec::Bzip2),
+            "xz" => Ok(Codec::Xz),
+            _ => Err(LogyError::argument("codec", s)),
+        }
+    }
+}
+
+impl Codec {
+    /// extension of compressed file name
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::None => "",
+            Codec::Gzip => ".gz",
+            Codec::Zstd => ".zst",
+            Codec::Bzip2 => ".bz2",
+            Codec::Xz => ".xz",
+        }
+    }
+
+    /// detect compression format by magic bytes at the head of file
+    pub fn detect(head: &[u8]) -> Codec {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Codec::Gzip
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Codec::Zstd
+        } else if head.starts_with(b"BZh") {
+            Codec::Bzip2
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Codec::Xz
+        } else {
+            Codec::None
         }
     }
+
+    /// wrap source with decoder, concatenated streams are all decoded
+    pub fn decoder<R: BufRead + Send + 'static>(
+        &self,
+        source: R,
+        zstd_dictionary: Option<&[u8]>,
+    ) -> io::Result<Box<dyn BufRead + Send>> {
+        Ok(match self {
+            Codec::None => Box::new(source),
+            Codec::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(source))),
+            Codec::Zstd => Box::new(BufReader::new(match zstd_dictionary {
+                Some(dictionary) => ZstdDecoder::with_dictionary(source, dictionary)?,
+                None => ZstdDecoder::with_buffer(source)?,
+            })),
+            Codec::Bzip2 => Box::new(BufReader::new(MultiBzDecoder::new(source))),
+            Codec::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(source))),
+        })
+    }
 }
 
 pub(crate) trait FileNameGetter {
@@ -142,11 +1035,11 @@ pub(crate) trait FileNameGetter {
 }
 
 pub(crate) enum Log {
-    EOF,
+    Eof,
     Line(String),
 }
 pub(crate) trait NextLogLineFinder {
-    fn next_log(&mut self) -> Log;
+    fn next_log(&mut self) -> Result<Log>;
 }
 
 impl FileNameGetter for WrappedFileReader {
@@ -156,52 +1049,425 @@ impl FileNameGetter for WrappedFileReader {
 }
 
 impl NextLogLineFinder for WrappedFileReader {
-    fn next_log(&mut self) -> Log {
-        let mut line = String::new();
-        if self.reader.read_line(&mut line).unwrap() == 0 {
-            Log::EOF
-        } else {
-            // remove line break at the end
-            line = line.trim_end().to_string();
-            if self.pattern.is_match(&line) {
-                if self.buffer.is_empty() {
-                    self.buffer.push(line);
-                    self.next_log()
+    fn next_log(&mut self) -> Result<Log> {
+        loop {
+            let mut line = String::new();
+            if self
+                .reader
+                .read_line(&mut line)
+                .map_err(|e| LogyError::io(&self.file, e))?
+                == 0
+            {
+                return Ok(if self.buffer.is_empty() {
+                    Log::Eof
                 } else {
-                    // next log, return all of previous lines
-                    let full_log = self.buffer.join("\n");
-                    self.buffer.clear();
-                    self.buffer.push(line);
-                    Log::Line(full_log)
-                }
+                    // end of file, return remained lines as last log
+                    self.take_buffer()
+                });
+            }
+            self.read_count += 1;
+
+            // remove line break at the end
+            let line = line.trim_end().to_string();
+            // continuation line like `{` of pretty printed object is not a JSON line
+            let json = if self.json_lines {
+                parse_json(&line)
             } else {
-                // same log, add to temp and read next line
+                None
+            };
+            let log_start = self.pattern.is_match(&line) || json.is_some();
+            if log_start && !self.buffer.is_empty() {
+                // next log, return all of previous lines
+                let full_log = self.take_buffer();
+                self.buffer_line_number = self.read_count;
+                self.buffer_json = json;
                 self.buffer.push(line);
-                self.next_log()
+                return Ok(full_log);
+            }
+
+            // same log, add to temp and read next line
+            if self.buffer.is_empty() {
+                self.buffer_line_number = self.read_count;
+                self.buffer_json = json;
+            }
+            self.buffer.push(line);
+        }
+    }
+}
+
+/// Extracts log time from the head line of a log with the prefix pattern and time format
+#[derive(Clone)]
+pub(crate) struct LogTimeParser {
+    // prefix pattern, log time is captured by group `time` or the first group
+    pattern: Regex,
+    // chrono format of captured log time
+    format: String,
+    // current year is added to log time without year
+    without_year: bool,
+    // log time has offset, otherwise it is local time of time zone
+    with_offset: bool,
+    zones: TimeZones,
+    // JSON pointer of log time in JSON logs
+    time_field: Option<String>,
+}
+
+impl LogTimeParser {
+    pub fn new(pattern: &str, format: &str) -> Result<LogTimeParser> {
+        Ok(LogTimeParser {
+            pattern: Regex::new(pattern).map_err(|e| LogyError::pattern(pattern, e))?,
+            format: format.to_string(),
+            without_year: !["%Y", "%y", "%G", "%s", "%F", "%D", "%c", "%+", "%x"]
+                .iter()
+                .any(|specifier| format.contains(specifier)),
+            with_offset: ["%z", "%:z", "%#z", "%+"]
+                .iter()
+                .any(|specifier| format.contains(specifier)),
+            zones: TimeZones::default(),
+            time_field: None,
+        })
+    }
+
+    /// time zones of log time without offset, UTC by default
+    pub fn with_time_zones(mut self, zones: &TimeZones) -> LogTimeParser {
+        self.zones = zones.clone();
+        self
+    }
+
+    /// read log time from field of JSON logs, other logs are still parsed by prefix pattern
+    pub fn with_json_fields(mut self, fields: &JsonFields) -> LogTimeParser {
+        self.time_field = fields.time.as_deref().map(field_pointer);
+        self
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.time_field.is_some()
+    }
+
+    /// parse log time in epoch milliseconds with default time zone, returns the cause when prefix or
+    /// time format not matched
+    pub fn parse(&self, line: &str) -> result::Result<i64, String> {
+        self.parse_file_log(STDIO, line)
+    }
+
+    /// parse log time in epoch milliseconds with time zone of source file
+    pub fn parse_file_log(&self, file: &str, line: &str) -> result::Result<i64, String> {
+        let json = self.time_field.as_ref().and_then(|_| parse_json(line));
+        self.parse_json_log(file, line, json.as_ref())
+    }
+
+    /// parse log time like `parse_file_log` with JSON object of log parsed already
+    pub fn parse_json_log(
+        &self,
+        file: &str,
+        line: &str,
+        json: Option<&Value>,
+    ) -> result::Result<i64, String> {
+        let zone = self.zones.zone(file);
+        if let Some(time_field) = &self.time_field {
+            if let Some(object) = json {
+                let value = object
+                    .pointer(time_field)
+                    .ok_or_else(|| format!("log time field {} not found", time_field))?;
+                return json_time(value, &self.format, &zone);
             }
         }
+
+        let log_time_string = self
+            .capture_time(line)
+            .ok_or_else(|| "log time not found by prefix pattern".to_string())?
+            .as_str();
+        let (log_time_string, format) = if self.without_year {
+            (
+                Cow::Owned(format!("{} {}", Local::now().year(), log_time_string)),
+                Cow::Owned(format!("%Y {}", self.format)),
+            )
+        } else {
+            (Cow::Borrowed(log_time_string), Cow::Borrowed(&self.format))
+        };
+        let log_time = if self.with_offset {
+            DateTime::parse_from_str(&log_time_string, &format).map(|time| time.timestamp_millis())
+        } else {
+            NaiveDateTime::parse_from_str(&log_time_string, &format)
+                .map(|time| local_to_instant(&time, &zone))
+        };
+        log_time.map_err(|e| format!("invalid log time {}: {}", log_time_string, e))
+    }
+
+    fn capture_time<'t>(&self, line: &'t str) -> Option<Match<'t>> {
+        self.pattern
+            .captures(line)
+            .and_then(|captures| captures.name("time").or_else(|| captures.get(1)))
+    }
+
+    /// byte position where log time ends in head line of log
+    pub fn time_end(&self, log: &str) -> Option<usize> {
+        self.capture_time(log).map(|captured| captured.end())
+    }
+
+    /// show log time in time zone, log time is replaced in place, or added before log when `annotate`
+    /// is true or log time is not in text. JSON logs get time field rewritten, or `local_time` field
+    /// added, to stay in JSON Lines
+    pub fn localize(&self, log: &str, time: i64, zone: &Tz, annotate: bool) -> String {
+        let local_time = zone.timestamp_millis(time);
+        let local_time_string = local_time.format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string();
+        if let Some(mut object) = parse_json(log) {
+            let time_value = match &self.time_field {
+                Some(time_field) if !annotate => object.pointer_mut(time_field),
+                _ => None,
+            };
+            match time_value {
+                Some(time_value) => *time_value = Value::String(local_time_string),
+                None => {
+                    if let Some(fields) = object.as_object_mut() {
+                        fields.shift_insert(
+                            0,
+                            LOCAL_TIME_FIELD.to_string(),
+                            Value::String(local_time_string),
+                        );
+                    }
+                }
+            }
+            let rest = log.find('\n').map_or("", |end| &log[end..]);
+            return format!("{}{}", object, rest);
+        }
+
+        match self.capture_time(log).filter(|_| !annotate) {
+            Some(captured) => format!(
+                "{}{}{}",
+                &log[..captured.start()],
+                local_time.format(&self.format),
+                &log[captured.end()..]
+            ),
+            None => format!("[{}] {}", local_time_string, log),
+        }
+    }
+}
+
+/// Time zones of log times without offset, by source file or default
+#[derive(Debug, Clone, Default)]
+pub struct TimeZones {
+    // UTC when not set
+    pub default: Option<Tz>,
+    pub files: HashMap<String, Tz>,
+}
+
+impl TimeZones {
+    /// parse time zones like `Asia/Taipei` as default, or `app.log=UTC` for source file
+    pub fn parse(values: &[&str]) -> Result<TimeZones> {
+        let mut zones = TimeZones::default();
+        for &value in values {
+            let (file, name) = match value.rsplit_once('=') {
+                Some((file, name)) => (Some(file), name),
+                None => (None, value),
+            };
+            let zone = name
+                .parse::<Tz>()
+                .map_err(|_| LogyError::argument("tz", value))?;
+            match file {
+                Some(file) => {
+                    zones.files.insert(file.to_string(), zone);
+                }
+                None => zones.default = Some(zone),
+            }
+        }
+        Ok(zones)
+    }
+
+    pub fn zone(&self, file: &str) -> Tz {
+        self.files
+            .get(file)
+            .copied()
+            .unwrap_or_else(|| self.default_zone())
+    }
+
+    pub fn default_zone(&self) -> Tz {
+        self.default.unwrap_or(Tz::UTC)
+    }
+}
+
+/// epoch milliseconds of local time in time zone, the earlier one is taken when local time is
+/// ambiguous, local time skipped by daylight saving is taken as time before the change
+pub(crate) fn local_to_instant(time: &NaiveDateTime, zone: &Tz) -> i64 {
+    match zone.from_local_datetime(time) {
+        LocalResult::Single(local) | LocalResult::Ambiguous(local, _) => local.timestamp_millis(),
+        LocalResult::None => {
+            let offset = zone.offset_from_utc_datetime(time).fix();
+            time.timestamp_millis() - offset.local_minus_utc() as i64 * 1000
+        }
+    }
+}
+
+/// milliseconds of local time in time zone, as if local time were UTC
+pub(crate) fn instant_to_local(time: i64, zone: &Tz) -> i64 {
+    let utc = NaiveDateTime::from_timestamp(
+        time.div_euclid(1000),
+        (time.rem_euclid(1000) * 1_000_000) as u32,
+    );
+    time + zone.offset_from_utc_datetime(&utc).fix().local_minus_utc() as i64 * 1000
+}
+
+/// Field paths of JSON logs, one JSON object per line, nested fields are separated by dot
+#[derive(Debug, Clone, Default)]
+pub struct JsonFields {
+    // log time in epoch milliseconds, epoch seconds or RFC 3339, JSON input is enabled when set
+    pub time: Option<String>,
+    pub trace: Option<String>,
+    pub level: Option<String>,
+}
+
+/// JSON object of first line of log, `None` when log is not JSON
+pub(crate) fn parse_json(log: &str) -> Option<Value> {
+    let first_line = log.lines().next()?;
+    if !first_line.starts_with('{') {
+        return None;
+    }
+    serde_json::from_str::<Value>(first_line)
+        .ok()
+        .filter(|value| value.is_object())
+}
+
+// field added to JSON logs for log time in output time zone
+const LOCAL_TIME_FIELD: &str = "local_time";
+
+/// convert field path like `context.traceId` to JSON pointer, `~` and `/` in names are escaped as
+/// RFC 6901
+pub(crate) fn field_pointer(path: &str) -> String {
+    path.split('.')
+        .map(|name| format!("/{}", name.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+/// text of string or number field
+pub(crate) fn json_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.to_string()),
+        Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+// epoch numbers less than this are seconds, otherwise milliseconds
+const EPOCH_SECONDS_LIMIT: f64 = 100_000_000_000.0;
+
+/// log time of epoch milliseconds or seconds, RFC 3339 or text in log time format
+fn json_time(value: &Value, format: &str, zone: &Tz) -> result::Result<i64, String> {
+    let epoch = match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.parse::<f64>().ok(),
+        _ => None,
+    };
+    if let Some(epoch) = epoch {
+        let millis = if epoch.abs() < EPOCH_SECONDS_LIMIT {
+            (epoch * 1000.0).round()
+        } else {
+            epoch.round()
+        };
+        // out of range epochs like `9e18` or `inf` can not be shown as date time
+        return Some(millis)
+            .filter(|millis| millis.is_finite() && millis.abs() < i64::MAX as f64)
+            .map(|millis| millis as i64)
+            .filter(|&millis| {
+                NaiveDateTime::from_timestamp_opt(
+                    millis.div_euclid(1000),
+                    (millis.rem_euclid(1000) * 1_000_000) as u32,
+                )
+                .is_some()
+            })
+            .ok_or_else(|| format!("log time {} out of range", value));
+    }
+
+    let text = value
+        .as_str()
+        .ok_or_else(|| format!("invalid log time {}", value))?;
+    DateTime::parse_from_rfc3339(text)
+        .map(|time| time.timestamp_millis())
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(text, format).map(|time| local_to_instant(&time, zone))
+        })
+        .map_err(|e| format!("invalid log time {}: {}", text, e))
+}
+
+/// Window of log time to process, logs out of window are skipped
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimeRange {
+    // inclusive start of log time
+    pub since: Option<i64>,
+    // exclusive end of log time
+    pub until: Option<i64>,
+    // logs of each file are in time order, reading stops once until is passed
+    pub sorted: bool,
+}
+
+impl TimeRange {
+    pub fn contains(&self, time: i64) -> bool {
+        self.since.is_none_or(|since| time >= since) && !self.is_after(time)
+    }
+
+    /// no more logs of the file can be in window
+    pub fn is_passed(&self, time: i64) -> bool {
+        self.sorted && self.is_after(time)
+    }
+
+    fn is_after(&self, time: i64) -> bool {
+        self.until.is_some_and(|until| time >= until)
     }
 }
 
-#[derive(Eq)]
+/// parse absolute time like 2021-09-27 01:00:00 in time zone, or time relative to now like -2h, into
+/// epoch milliseconds
+pub(crate) fn parse_time(value: &str, zone: &Tz) -> result::Result<i64, String> {
+    if let Some(duration) = value.strip_prefix('-') {
+        return Ok(Utc::now().timestamp_millis() - parse_duration(duration)?);
+    }
+    const FORMATS: [&str; 4] = [
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M",
+    ];
+    FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .map(|date| date.and_hms(0, 0, 0))
+        })
+        .map(|time| local_to_instant(&time, zone))
+        .ok_or_else(|| format!("invalid time {}", value))
+}
+
+/// A log read from source file, ordered by log time, then source file and read sequence
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub(crate) struct LogLine {
+    time: i64,
     file: String,
+    seq: u64,
     line: String,
 }
 
 impl LogLine {
-    pub fn new(file: &str, line: &str) -> LogLine {
+    pub fn new(time: i64, file: &str, seq: u64, line: &str) -> LogLine {
         LogLine {
+            time,
             file: file.to_string(),
+            seq,
             line: line.to_string(),
         }
     }
+    pub fn time(&self) -> i64 {
+        self.time
+    }
     pub fn filename(&self) -> String {
         self.file.to_string()
     }
     pub fn value(&self) -> String {
         self.line.to_string()
     }
+    /// bytes of log kept in memory, roughly
+    pub fn size(&self) -> usize {
+        self.file.len() + self.line.len() + 64
+    }
 }
 
 impl Display for LogLine {
@@ -209,20 +1475,3 @@ impl Display for LogLine {
         write!(f, "{}", self.line)
     }
 }
-
-impl Ord for LogLine {
-    fn cmp(&self, other: &Self) -> cmp::Ordering {
-        self.line.cmp(&other.line)
-    }
-}
-
-impl PartialOrd for LogLine {
-    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
-impl PartialEq for LogLine {
-    fn eq(&self, other: &Self) -> bool {
-        self.line == other.line
-    }
-}
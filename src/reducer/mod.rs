@@ -1,130 +1,479 @@
-use chrono::{Duration, NaiveDateTime};
-use log::debug;
-use regex::Regex;
+use chrono_tz::Tz;
+use log::{debug, info};
+use serde_json::Value;
 use std::{
-    collections::{BTreeSet, HashMap},
-    io::Result,
-    sync::mpsc,
-    thread,
+    collections::{BTreeSet, VecDeque},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
 };
 
+use super::dedup::{DedupOptions, Deduplicator};
+use super::errors::{ErrorHandler, ErrorPolicy, LogyError, Result};
+use super::filter::{FilterOptions, LogFilter};
 use super::models::{
-    FileNameGetter, Log, LogLine, NextLogLineFinder, WrappedFileReader, WrappedFileWriter,
+    FileNameGetter, JsonFields, Log, LogLine, LogTimeParser, NextLogLineFinder, TimeRange,
+    TimeZones, WrappedFileReader, WriterOptions, WriterPool,
 };
+use super::sorter;
+use super::tagger::SourceTagger;
+
+// logs read from a file or sent to receiver at a time
+const BATCH_SIZE: usize = 256;
+// batches buffered in channel to receiver
+pub(crate) const CHANNEL_BATCHES: usize = 16;
+
+/// Logs received in batches, in order of log time, from the thread reading them
+pub(crate) struct LogBatches {
+    rx: Receiver<Vec<Result<LogLine>>>,
+    handle: JoinHandle<()>,
+}
+
+impl LogBatches {
+    pub fn new(rx: Receiver<Vec<Result<LogLine>>>, handle: JoinHandle<()>) -> LogBatches {
+        LogBatches { rx, handle }
+    }
+
+    /// batches until all logs are read
+    pub fn iter(&self) -> mpsc::Iter<'_, Vec<Result<LogLine>>> {
+        self.rx.iter()
+    }
+
+    /// wait for the reading thread after batches are received, so a panic of it is not taken as end
+    /// of logs
+    pub fn join(self) -> Result<()> {
+        drop(self.rx);
+        self.handle.join().map_err(LogyError::panic)
+    }
+}
+
+/// Selects logs to read by head line and its JSON object, other logs are skipped before log time is
+/// parsed
+pub(crate) type LogSelector = Arc<dyn Fn(&str, Option<&Value>) -> bool + Send + Sync>;
+
+pub struct ReduceOptions {
+    // prefix pattern to determine start of log
+    pub pattern: String,
+    pub log_time_format: String,
+    pub output_file_pattern: String,
+    pub writer_options: WriterOptions,
+    pub error_policy: ErrorPolicy,
+    // logs out of time range are skipped
+    pub time_range: TimeRange,
+    pub filter: FilterOptions,
+    pub json: JsonFields,
+    // time zones of log times without offset
+    pub time_zones: TimeZones,
+    // log times are shown in this time zone
+    pub out_time_zone: Option<Tz>,
+    // log time is added before log instead of being replaced
+    pub annotate_time: bool,
+    // template to tag logs with source files, like `[{basename}] `
+    pub tag_source: Option<String>,
+    // named captures of file path used as placeholders of tag template
+    pub source_pattern: Option<String>,
+    // duplicate logs are removed when set
+    pub dedup: Option<DedupOptions>,
+    // files are not in time order, logs are sorted within this bytes of memory
+    pub sort_memory: Option<usize>,
+    // readers of files in parallel, cores of machine by default
+    pub threads: Option<usize>,
+    // zstd dictionary of compressed inputs
+    pub zstd_dictionary: Option<Arc<Vec<u8>>>,
+}
 
 /// read multiple files and compress output
-pub fn reduce_logs(
-    files: &Vec<&str>,
-    pattern: &str,
-    log_time_format: &str,
-    output_file_pattern: &str,
-    compress_level: u32,
-) -> Result<()> {
-    let mut writer = WrappedFileWriter::new(output_file_pattern, compress_level);
+pub fn reduce_logs(files: &[&str], options: &ReduceOptions) -> Result<()> {
+    let parser = LogTimeParser::new(&options.pattern, &options.log_time_format)?
+        .with_json_fields(&options.json)
+        .with_time_zones(&options.time_zones);
+    let filter = LogFilter::new(&options.filter, &options.json)?;
+    let mut writer = WriterPool::new(&options.output_file_pattern, options.writer_options.clone())?;
+    let mut error_handler = ErrorHandler::new(options.error_policy);
 
-    let (tx, rx) = mpsc::sync_channel::<String>(100);
+    let mut tagger = match &options.tag_source {
+        Some(template) => Some(SourceTagger::new(
+            template,
+            options.source_pattern.as_deref(),
+        )?),
+        None => None,
+    };
+    let mut deduplicator = match &options.dedup {
+        Some(dedup) => Some(Deduplicator::new(dedup)?),
+        None => None,
+    };
+    let output_parser = parser.clone();
+    let logs = match options.sort_memory {
+        Some(memory_limit) => sorter::sort_files(
+            files,
+            &options.pattern,
+            parser,
+            options.time_range,
+            memory_limit,
+            options.zstd_dictionary.clone(),
+        ),
+        None => merge_files(
+            files,
+            &options.pattern,
+            parser,
+            options.time_range,
+            options.threads,
+            options.zstd_dictionary.clone(),
+            None,
+        ),
+    };
+
+    for log in logs.iter().flatten() {
+        match log {
+            Ok(log)
+                if filter.accept(&log.value())
+                    && deduplicator.as_mut().is_none_or(|deduplicator| {
+                        deduplicator.accept(log.time(), &log.filename(), &log.value())
+                    }) =>
+            {
+                let mut value = log.value();
+                if let Some(zone) = &options.out_time_zone {
+                    value = output_parser.localize(&value, log.time(), zone, options.annotate_time);
+                }
+                if let Some(tagger) = tagger.as_mut() {
+                    value = tagger.apply(&value, &log.filename(), output_parser.time_end(&value));
+                }
+                writer.write(log.time(), &value)?
+            }
+            Ok(_) => {}
+            Err(e @ LogyError::MalformedLog { .. }) => error_handler.handle(e)?,
+            Err(e) => return Err(e),
+        }
+    }
+    logs.join()?;
+
+    writer.flush()?;
+    error_handler.report();
+    if let Some(deduplicator) = &deduplicator {
+        info!("{} duplicate logs removed", deduplicator.removed());
+    }
+
+    Ok(())
+}
+
+/// merge logs of files by log time in another thread with a pool of `threads` readers, cores of
+/// machine by default, logs are received in batches and malformed logs as errors in merge order
+///
+/// Readers parse logs of a file in batches, each file is read ahead by one batch at most, so a pool
+/// smaller than count of files is enough and merge order is kept.
+pub(crate) fn merge_files(
+    files: &[&str],
+    pattern: &str,
+    parser: LogTimeParser,
+    time_range: TimeRange,
+    threads: Option<usize>,
+    zstd_dictionary: Option<Arc<Vec<u8>>>,
+    select: Option<LogSelector>,
+) -> LogBatches {
+    let (tx, rx) = mpsc::sync_channel::<Vec<Result<LogLine>>>(CHANNEL_BATCHES);
     let files = files
         .iter()
         .map(|&s| s.to_string())
         .collect::<Vec<String>>();
-    let pattern = pattern.to_string();
-    let parse_log_time_pattern = Regex::new(&pattern.clone()).unwrap();
+    let options = ReadOptions {
+        pattern: pattern.to_string(),
+        parser,
+        time_range,
+        zstd_dictionary,
+        select,
+    };
+    let threads = threads
+        .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1);
 
-    thread::spawn(move || {
-        let mut sorted_set: BTreeSet<LogLine> = BTreeSet::new();
-        let mut readers = files
+    let handle = thread::spawn(move || {
+        if let Err(e) = merge_logs(&files, options, threads, &tx) {
+            // receiver may be closed already, nothing to do
+            let _ = tx.send(vec![Err(e)]);
+        }
+    });
+
+    LogBatches::new(rx, handle)
+}
+
+/// How logs of each file are read by pool
+struct ReadOptions {
+    pattern: String,
+    parser: LogTimeParser,
+    time_range: TimeRange,
+    zstd_dictionary: Option<Arc<Vec<u8>>>,
+    select: Option<LogSelector>,
+}
+
+/// Logs of a file read by pool, shared by readers one at a time
+struct BatchReader {
+    file: String,
+    reader: Option<WrappedFileReader>,
+    seq: u64,
+    // end of file or time range is reached
+    finished: bool,
+}
+
+/// A batch of logs read from a file with malformed logs in place as errors, empty batch for end of
+/// file
+struct Batch {
+    index: usize,
+    logs: Result<Vec<Result<LogLine>>>,
+}
+
+impl BatchReader {
+    fn read_batch(&mut self, options: &ReadOptions) -> Result<Vec<Result<LogLine>>> {
+        if self.reader.is_none() {
+            self.reader = Some(
+                WrappedFileReader::new(
+                    &self.file,
+                    &options.pattern,
+                    options.zstd_dictionary.as_deref().map(Vec::as_slice),
+                )?
+                .with_json_lines(options.parser.is_json()),
+            );
+        }
+        let reader = self.reader.as_mut().unwrap();
+        let mut logs = Vec::with_capacity(BATCH_SIZE);
+        while logs.len() < BATCH_SIZE && !self.finished {
+            let log = read_next(
+                reader,
+                &options.parser,
+                &options.time_range,
+                options.select.as_ref(),
+                &mut self.seq,
+                &mut |error| {
+                    logs.push(Err(error));
+                    true
+                },
+            )?;
+            match log {
+                Some(log) => logs.push(Ok(log)),
+                None => self.finished = true,
+            }
+        }
+        Ok(logs)
+    }
+}
+
+/// merge logs of files by log time and send to channel in batches, malformed logs are sent as
+/// errors
+fn merge_logs(
+    files: &[String],
+    options: ReadOptions,
+    threads: usize,
+    tx: &SyncSender<Vec<Result<LogLine>>>,
+) -> Result<()> {
+    let readers = Arc::new(
+        files
             .iter()
-            .map(|path| {
-                (
-                    path.to_string(),
-                    WrappedFileReader::new(path.as_str(), pattern.as_str(), false),
-                )
+            .map(|file| {
+                Mutex::new(BatchReader {
+                    file: file.to_string(),
+                    reader: None,
+                    seq: 0,
+                    finished: false,
+                })
             })
-            .collect::<HashMap<String, WrappedFileReader>>();
-
-        let file_count = files.len();
-        let mut file_done_count = 0;
-
-        // read head line from files
-        let empty_files = readers
-            .values_mut()
-            .filter_map(|reader| {
-                let filename = reader.filename();
-                if let Log::Line(line) = reader.next_log() {
-                    sorted_set.insert(LogLine::new(&filename, &line));
-                    None
-                } else {
-                    // read to end of file
-                    // remove reader from list
-                    Some(filename)
-                }
-            })
-            .collect::<Vec<String>>();
+            .collect::<Vec<Mutex<BatchReader>>>(),
+    );
+    let (job_tx, job_rx) = mpsc::channel::<usize>();
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let (batch_tx, batch_rx) = mpsc::channel::<Batch>();
+    let options = Arc::new(options);
+    for _ in 0..threads.clamp(1, files.len().max(1)) {
+        let readers = Arc::clone(&readers);
+        let job_rx = Arc::clone(&job_rx);
+        let batch_tx = batch_tx.clone();
+        let options = Arc::clone(&options);
+        thread::spawn(move || loop {
+            let index = match job_rx.lock().unwrap().recv() {
+                Ok(index) => index,
+                // merge finished
+                Err(_) => return,
+            };
+            let mut reader = readers[index].lock().unwrap();
+            // a panic is reported as error of the file, otherwise merge waits for the batch forever
+            let logs = panic::catch_unwind(AssertUnwindSafe(|| reader.read_batch(&options)))
+                .unwrap_or_else(|payload| Err(LogyError::panic(payload)));
+            // send while holding reader, batches of a file are received in order
+            if batch_tx.send(Batch { index, logs }).is_err() {
+                return;
+            }
+        });
+    }
 
-        for empty_file in empty_files {
-            file_done_count = file_done_count + 1;
-            debug!(
-                "finish reader {}/{} {}",
-                file_done_count, file_count, empty_file
-            );
-            readers.remove(&empty_file);
+    // pool is gone when all readers stopped
+    drop(batch_tx);
+
+    let mut merger = BatchMerger {
+        buffers: (0..files.len()).map(|_| VecDeque::new()).collect(),
+        reading: vec![false; files.len()],
+        done: vec![false; files.len()],
+        job_tx,
+        batch_rx,
+    };
+    let mut sender = BatchSender::new(tx);
+    // head log of each file, index tells apart equal logs of a file given twice
+    let mut heads = BTreeSet::new();
+    for index in 0..files.len() {
+        merger.request(index);
+    }
+    for index in 0..files.len() {
+        if let Some(log) = merger.next(index, &mut sender)? {
+            heads.insert((log, index));
         }
+    }
 
-        while !readers.is_empty() || !sorted_set.is_empty() {
-            if let Some((filename, line)) =
-                sorted_set.iter().next().map(|v| (v.filename(), v.value()))
-            {
-                let v = LogLine::new(&filename, &line);
-                sorted_set.remove(&v);
-                tx.send(line).unwrap();
-
-                if let Some(reader) = readers.get_mut(&filename) {
-                    if let Log::Line(line) = reader.next_log() {
-                        sorted_set.insert(LogLine::new(&filename, &line));
-                    } else {
-                        // read to end of file
-                        // remove reader from list
-                        file_done_count = file_done_count + 1;
-                        debug!(
-                            "finish reader {}/{} {}",
-                            file_done_count, file_count, filename
-                        );
-                        readers.remove(&filename);
-                    }
-                } else if let Some(reader) = readers.values_mut().last() {
-                    if let Log::Line(line) = reader.next_log() {
-                        sorted_set.insert(LogLine::new(&filename, &line));
-                    } else {
-                        // read to end of file
-                        // remove reader from list
-                        file_done_count = file_done_count + 1;
-                        debug!(
-                            "finish reader {}/{} {}",
-                            file_done_count, file_count, filename
-                        );
-                        readers.remove(&filename);
-                    }
-                }
+    while let Some((log, index)) = heads.pop_first() {
+        if !sender.send(log) {
+            // receiver stopped
+            return Ok(());
+        }
+
+        match merger.next(index, &mut sender)? {
+            Some(log) => {
+                heads.insert((log, index));
             }
+            None => debug!("finish reader {}", files[index]),
         }
-    });
+    }
+    sender.flush();
 
-    let seconds_an_hour = Duration::hours(1).num_seconds();
-    for value in rx {
-        let log_time_string = parse_log_time_pattern
-            .captures(&value)
-            .unwrap()
-            .get(1)
-            .unwrap()
-            .as_str()
-            .to_string();
-        let log_time = NaiveDateTime::parse_from_str(&log_time_string, log_time_format).unwrap();
-        let log_hour = log_time.timestamp() / seconds_an_hour;
+    Ok(())
+}
+
+/// Sends logs to receiver in batches
+pub(crate) struct BatchSender<'a> {
+    tx: &'a SyncSender<Vec<Result<LogLine>>>,
+    batch: Vec<Result<LogLine>>,
+}
 
-        writer.write(log_hour, &value);
+impl<'a> BatchSender<'a> {
+    pub fn new(tx: &'a SyncSender<Vec<Result<LogLine>>>) -> BatchSender<'a> {
+        BatchSender {
+            tx,
+            batch: Vec::with_capacity(BATCH_SIZE),
+        }
     }
 
-    writer.flush();
+    /// returns false when receiver stopped
+    pub fn send(&mut self, log: LogLine) -> bool {
+        self.send_result(Ok(log))
+    }
 
-    Ok(())
+    /// send malformed log in order of logs, returns false when receiver stopped
+    pub fn send_error(&mut self, error: LogyError) -> bool {
+        self.send_result(Err(error))
+    }
+
+    fn send_result(&mut self, log: Result<LogLine>) -> bool {
+        self.batch.push(log);
+        self.batch.len() < BATCH_SIZE || self.flush()
+    }
+
+    /// send logs not sent yet, returns false when receiver stopped
+    pub fn flush(&mut self) -> bool {
+        if self.batch.is_empty() {
+            return true;
+        }
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH_SIZE));
+        self.tx.send(batch).is_ok()
+    }
+}
+
+/// Takes logs of each file from batches read by pool
+struct BatchMerger {
+    buffers: Vec<VecDeque<Result<LogLine>>>,
+    // a batch of file is being read
+    reading: Vec<bool>,
+    // end of file is reached
+    done: Vec<bool>,
+    job_tx: mpsc::Sender<usize>,
+    batch_rx: Receiver<Batch>,
+}
+
+impl BatchMerger {
+    /// read next batch of file unless it is being read, or read to end already
+    fn request(&mut self, index: usize) {
+        if !self.reading[index] && !self.done[index] {
+            self.reading[index] = true;
+            // pool stops only after merge finished
+            self.job_tx.send(index).unwrap();
+        }
+    }
+
+    /// next log of file, waits for batches when none is buffered, malformed logs before it are sent
+    /// as errors, `None` when end of file or receiver stopped
+    fn next(&mut self, index: usize, sender: &mut BatchSender) -> Result<Option<LogLine>> {
+        loop {
+            if let Some(log) = self.buffers[index].pop_front() {
+                // read ahead when the last batch is being taken
+                if self.buffers[index].len() < BATCH_SIZE / 2 {
+                    self.request(index);
+                }
+                match log {
+                    Ok(log) => return Ok(Some(log)),
+                    Err(e) => {
+                        if !sender.send_error(e) {
+                            return Ok(None);
+                        }
+                        continue;
+                    }
+                }
+            }
+            if self.done[index] {
+                return Ok(None);
+            }
+            self.request(index);
+
+            let batch = self.batch_rx.recv().map_err(|_| LogyError::Panic {
+                cause: "all readers stopped".to_string(),
+            })?;
+            self.reading[batch.index] = false;
+            let logs = batch.logs?;
+            if logs.is_empty() {
+                self.done[batch.index] = true;
+            }
+            self.buffers[batch.index].extend(logs);
+        }
+    }
+}
+
+/// read next selected log with parsable log time in time range, malformed logs are passed to
+/// `on_malformed`, `None` when end of file, time range passed or `on_malformed` returns false
+pub(crate) fn read_next(
+    reader: &mut WrappedFileReader,
+    parser: &LogTimeParser,
+    time_range: &TimeRange,
+    select: Option<&LogSelector>,
+    seq: &mut u64,
+    on_malformed: &mut dyn FnMut(LogyError) -> bool,
+) -> Result<Option<LogLine>> {
+    while let Log::Line(line) = reader.next_log()? {
+        if select.is_some_and(|select| !select(&line, reader.json())) {
+            continue;
+        }
+        match parser.parse_json_log(&reader.filename(), &line, reader.json()) {
+            Ok(time) if time_range.is_passed(time) => {
+                debug!("time range passed, stop reading {}", reader.filename());
+                return Ok(None);
+            }
+            Ok(time) if !time_range.contains(time) => {}
+            Ok(time) => {
+                *seq += 1;
+                return Ok(Some(LogLine::new(time, &reader.filename(), *seq, &line)));
+            }
+            Err(cause) => {
+                let error = LogyError::malformed(&reader.filename(), reader.line_number(), &cause);
+                if !on_malformed(error) {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+    Ok(None)
 }
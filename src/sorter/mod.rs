@@ -0,0 +1,275 @@
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::debug;
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Lines, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SyncSender},
+        Arc,
+    },
+    thread,
+};
+
+use super::errors::{LogyError, Result};
+use super::models::{LogLine, LogTimeParser, TimeRange, WrappedFileReader};
+use super::reducer::{read_next, BatchSender, LogBatches, CHANNEL_BATCHES};
+
+// runs merged at once, more runs are merged into fewer runs first
+const MAX_MERGE_RUNS: usize = 64;
+
+// sorts in this process, to name temp directory of each sort
+static SORT_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// sort logs of files by log time in another thread with at most `memory_limit` bytes of logs in
+/// memory, malformed logs are received as errors
+///
+/// Logs are read in chunks up to the memory limit, each chunk is sorted and spilled to a compressed
+/// run file, then all runs are merged.
+pub(crate) fn sort_files(
+    files: &[&str],
+    pattern: &str,
+    parser: LogTimeParser,
+    time_range: TimeRange,
+    memory_limit: usize,
+    zstd_dictionary: Option<Arc<Vec<u8>>>,
+) -> LogBatches {
+    let (tx, rx) = mpsc::sync_channel::<Vec<Result<LogLine>>>(CHANNEL_BATCHES);
+    let files = files
+        .iter()
+        .map(|&s| s.to_string())
+        .collect::<Vec<String>>();
+    let pattern = pattern.to_string();
+
+    let handle = thread::spawn(move || {
+        let mut sorter = ExternalSorter::new(memory_limit);
+        let result = sorter.sort(
+            &files,
+            &pattern,
+            &parser,
+            &time_range,
+            zstd_dictionary.as_deref().map(Vec::as_slice),
+            &tx,
+        );
+        sorter.clean();
+        if let Err(e) = result {
+            // receiver may be closed already, nothing to do
+            let _ = tx.send(vec![Err(e)]);
+        }
+    });
+
+    LogBatches::new(rx, handle)
+}
+
+struct ExternalSorter {
+    memory_limit: usize,
+    spill_dir: PathBuf,
+    run_count: u64,
+}
+
+impl ExternalSorter {
+    fn new(memory_limit: usize) -> ExternalSorter {
+        ExternalSorter {
+            memory_limit,
+            spill_dir: env::temp_dir().join(format!(
+                "logy-sort-{}-{}",
+                process::id(),
+                SORT_COUNT.fetch_add(1, Ordering::Relaxed)
+            )),
+            run_count: 0,
+        }
+    }
+
+    fn sort(
+        &mut self,
+        files: &[String],
+        pattern: &str,
+        parser: &LogTimeParser,
+        time_range: &TimeRange,
+        zstd_dictionary: Option<&[u8]>,
+        tx: &SyncSender<Vec<Result<LogLine>>>,
+    ) -> Result<()> {
+        let mut chunk = Vec::new();
+        let mut chunk_size = 0;
+        let mut runs = Vec::new();
+        let mut seq: u64 = 0;
+        for file in files {
+            let mut reader = WrappedFileReader::new(file, pattern, zstd_dictionary)?
+                .with_json_lines(parser.is_json());
+            // order of logs is not known until sorted, malformed logs are sent at once
+            let mut on_malformed = |error| tx.send(vec![Err(error)]).is_ok();
+            while let Some(log) = read_next(
+                &mut reader,
+                parser,
+                time_range,
+                None,
+                &mut seq,
+                &mut on_malformed,
+            )? {
+                chunk_size += log.size();
+                chunk.push(log);
+                if chunk_size >= self.memory_limit {
+                    runs.push(self.spill(&mut chunk)?);
+                    chunk_size = 0;
+                }
+            }
+        }
+
+        if runs.is_empty() {
+            // all logs fit in memory
+            chunk.sort();
+            let mut sender = BatchSender::new(tx);
+            for log in chunk {
+                if !sender.send(log) {
+                    return Ok(());
+                }
+            }
+            sender.flush();
+            return Ok(());
+        }
+        if !chunk.is_empty() {
+            runs.push(self.spill(&mut chunk)?);
+        }
+
+        while runs.len() > MAX_MERGE_RUNS {
+            let mut merged_runs = Vec::new();
+            for group in runs.chunks(MAX_MERGE_RUNS) {
+                let run = self.next_run()?;
+                let mut writer = RunWriter::create(&run)?;
+                merge_runs(group, |log| writer.write(&log).map(|_| true))?;
+                writer.finish()?;
+                merged_runs.push(run);
+            }
+            for run in &runs {
+                remove_run(run)?;
+            }
+            runs = merged_runs;
+        }
+        let mut sender = BatchSender::new(tx);
+        merge_runs(&runs, |log| Ok(sender.send(log)))?;
+        sender.flush();
+        Ok(())
+    }
+
+    /// sort chunk and write it to a new run
+    fn spill(&mut self, chunk: &mut Vec<LogLine>) -> Result<PathBuf> {
+        chunk.sort();
+        let run = self.next_run()?;
+        debug!("spill {} logs to {}", chunk.len(), run.to_string_lossy());
+        let mut writer = RunWriter::create(&run)?;
+        for log in chunk.drain(..) {
+            writer.write(&log)?;
+        }
+        writer.finish()?;
+        Ok(run)
+    }
+
+    fn next_run(&mut self) -> Result<PathBuf> {
+        fs::create_dir_all(&self.spill_dir)
+            .map_err(|e| LogyError::io(&self.spill_dir.to_string_lossy(), e))?;
+        self.run_count += 1;
+        Ok(self.spill_dir.join(format!("{}.run.gz", self.run_count)))
+    }
+
+    fn clean(&self) {
+        if self.run_count > 0 {
+            if let Err(e) = fs::remove_dir_all(&self.spill_dir) {
+                debug!("fail to remove {}: {}", self.spill_dir.to_string_lossy(), e);
+            }
+        }
+    }
+}
+
+/// merge sorted runs and pass logs in order to `emit` until it returns false
+fn merge_runs(runs: &[PathBuf], mut emit: impl FnMut(LogLine) -> Result<bool>) -> Result<()> {
+    let mut readers = runs
+        .iter()
+        .map(|run| RunReader::open(run))
+        .collect::<Result<Vec<RunReader>>>()?;
+    // head log of each run
+    let mut heads = BTreeMap::new();
+    for (index, reader) in readers.iter_mut().enumerate() {
+        if let Some(log) = reader.next()? {
+            heads.insert(log, index);
+        }
+    }
+
+    while let Some((log, index)) = heads.pop_first() {
+        if !emit(log)? {
+            return Ok(());
+        }
+        if let Some(log) = readers[index].next()? {
+            heads.insert(log, index);
+        }
+    }
+    Ok(())
+}
+
+fn remove_run(run: &Path) -> Result<()> {
+    fs::remove_file(run).map_err(|e| LogyError::io(&run.to_string_lossy(), e))
+}
+
+/// Writes sorted logs to a compressed run file, one JSON record per log
+struct RunWriter {
+    path: String,
+    writer: GzEncoder<BufWriter<File>>,
+}
+
+impl RunWriter {
+    fn create(run: &Path) -> Result<RunWriter> {
+        let path = run.to_string_lossy().to_string();
+        let file = File::create(run).map_err(|e| LogyError::io(&path, e))?;
+        Ok(RunWriter {
+            writer: GzEncoder::new(BufWriter::new(file), Compression::fast()),
+            path,
+        })
+    }
+
+    fn write(&mut self, log: &LogLine) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, log)
+            .map_err(|e| LogyError::io(&self.path, e.into()))?;
+        self.writer
+            .write_all(b"\n")
+            .map_err(|e| LogyError::io(&self.path, e))
+    }
+
+    fn finish(self) -> Result<()> {
+        let path = self.path;
+        self.writer
+            .finish()
+            .and_then(|mut writer| writer.flush())
+            .map_err(|e| LogyError::io(&path, e))
+    }
+}
+
+struct RunReader {
+    path: String,
+    lines: Lines<BufReader<GzDecoder<File>>>,
+}
+
+impl RunReader {
+    fn open(run: &Path) -> Result<RunReader> {
+        let path = run.to_string_lossy().to_string();
+        let file = File::open(run).map_err(|e| LogyError::io(&path, e))?;
+        Ok(RunReader {
+            lines: BufReader::new(GzDecoder::new(file)).lines(),
+            path,
+        })
+    }
+
+    fn next(&mut self) -> Result<Option<LogLine>> {
+        match self.lines.next() {
+            Some(line) => {
+                let line = line.map_err(|e| LogyError::io(&self.path, e))?;
+                serde_json::from_str(&line)
+                    .map(Some)
+                    .map_err(|e| LogyError::io(&self.path, e.into()))
+            }
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use chrono::{Duration, NaiveDateTime};
+use std::{collections::BTreeMap, fmt::Write};
+
+// upper bounds of histogram buckets in milliseconds, last bucket has no upper bound
+const BUCKETS: [i64; 14] = [
+    10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000, 20000, 30000, 60000, 300000,
+];
+const PERCENTILES: [f64; 5] = [50.0, 90.0, 95.0, 99.0, 99.9];
+const BAR_WIDTH: usize = 40;
+
+/// Durations of traces for latency distribution
+#[derive(Debug)]
+pub(crate) struct LatencyStats {
+    // durations in milliseconds
+    durations: Vec<i64>,
+    // durations grouped by hour of trace start time, only kept when hourly stats are wanted
+    hourly: Option<BTreeMap<i64, Vec<i64>>>,
+}
+
+impl LatencyStats {
+    pub fn new(hourly: bool) -> LatencyStats {
+        LatencyStats {
+            durations: Vec::new(),
+            hourly: hourly.then(BTreeMap::new),
+        }
+    }
+
+    pub fn add(&mut self, start_time: i64, duration: i64) {
+        self.durations.push(duration);
+        if let Some(hourly) = &mut self.hourly {
+            let hour = start_time.div_euclid(Duration::hours(1).num_milliseconds());
+            hourly.entry(hour).or_default().push(duration);
+        }
+    }
+
+    pub fn extend(&mut self, other: LatencyStats) {
+        self.durations.extend(other.durations);
+        if let (Some(hourly), Some(other)) = (&mut self.hourly, other.hourly) {
+            for (hour, durations) in other {
+                hourly.entry(hour).or_default().extend(durations);
+            }
+        }
+    }
+
+    /// summary and histogram of all traces, followed by each hour when hourly stats are kept
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        render_durations(&mut output, &self.durations);
+        if let Some(hourly) = &self.hourly {
+            for (hour, durations) in hourly {
+                let time = NaiveDateTime::from_timestamp(hour * 3600, 0);
+                writeln!(output, "\n==== {} ====", time.format("%Y-%m-%d %H:00")).unwrap();
+                render_durations(&mut output, durations);
+            }
+        }
+        output
+    }
+}
+
+fn render_durations(output: &mut String, durations: &[i64]) {
+    let mut sorted = durations.to_vec();
+    sorted.sort_unstable();
+
+    writeln!(output, "count: {}", sorted.len()).unwrap();
+    if sorted.is_empty() {
+        return;
+    }
+    let mean = sorted.iter().map(|&d| d as f64).sum::<f64>() / sorted.len() as f64;
+    writeln!(output, "min: {} ms", sorted[0]).unwrap();
+    writeln!(output, "max: {} ms", sorted[sorted.len() - 1]).unwrap();
+    writeln!(output, "mean: {:.2} ms", mean).unwrap();
+    for &p in &PERCENTILES {
+        writeln!(output, "p{}: {} ms", p, percentile(&sorted, p)).unwrap();
+    }
+
+    let mut counts = [0usize; BUCKETS.len() + 1];
+    for &duration in &sorted {
+        let index = BUCKETS
+            .iter()
+            .position(|&bound| duration < bound)
+            .unwrap_or(BUCKETS.len());
+        counts[index] += 1;
+    }
+    let max_count = counts.iter().copied().max().unwrap_or(0);
+    for (index, &count) in counts.iter().enumerate() {
+        let label = match BUCKETS.get(index) {
+            Some(bound) => format!("< {} ms", bound),
+            None => format!(">= {} ms", BUCKETS[BUCKETS.len() - 1]),
+        };
+        // max_count is not zero as durations are not empty
+        let bar = (count * BAR_WIDTH).div_ceil(max_count);
+        writeln!(
+            output,
+            "{:>12} |{:<width$}| {}",
+            label,
+            "#".repeat(bar),
+            count,
+            width = BAR_WIDTH
+        )
+        .unwrap();
+    }
+}
+
+/// nearest-rank percentile of sorted values
+fn percentile(sorted: &[i64], p: f64) -> i64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
@@ -0,0 +1,109 @@
+use regex::{Captures, Regex};
+use serde_json::Value;
+use std::{collections::HashMap, path::Path};
+
+use super::errors::{LogyError, Result};
+use super::models::parse_json;
+
+// field of JSON logs to hold the tag
+const SOURCE_FIELD: &str = "source";
+
+/// Tags logs with their source files by a template like `[{basename}] `
+///
+/// Placeholders are `{file}`, `{basename}` and named captures of the path pattern, e.g.
+/// `(?P<pod>[^/]+)\.log$` for `{pod}`.
+pub(crate) struct SourceTagger {
+    template: String,
+    placeholder: Regex,
+    path_pattern: Option<Regex>,
+    // rendered tags by file
+    tags: HashMap<String, String>,
+}
+
+impl SourceTagger {
+    pub fn new(template: &str, path_pattern: Option<&str>) -> Result<SourceTagger> {
+        let path_pattern = match path_pattern {
+            Some(pattern) => Some(Regex::new(pattern).map_err(|e| LogyError::pattern(pattern, e))?),
+            None => None,
+        };
+        let placeholder = Regex::new(r"\{(\w+)\}").unwrap();
+        for captures in placeholder.captures_iter(template) {
+            let name = &captures[1];
+            let known = name == "file"
+                || name == "basename"
+                || path_pattern
+                    .as_ref()
+                    .is_some_and(|re| re.capture_names().flatten().any(|n| n == name));
+            if !known {
+                return Err(LogyError::argument("tag-source", name));
+            }
+        }
+
+        Ok(SourceTagger {
+            template: template.to_string(),
+            placeholder,
+            path_pattern,
+            tags: HashMap::new(),
+        })
+    }
+
+    /// tag of file rendered from template
+    pub fn tag(&mut self, file: &str) -> &str {
+        if !self.tags.contains_key(file) {
+            let tag = self.render(file);
+            self.tags.insert(file.to_string(), tag);
+        }
+        &self.tags[file]
+    }
+
+    fn render(&self, file: &str) -> String {
+        let captures = self
+            .path_pattern
+            .as_ref()
+            .and_then(|pattern| pattern.captures(file));
+        self.placeholder
+            .replace_all(
+                &self.template,
+                |placeholder: &Captures| match &placeholder[1] {
+                    "file" => file.to_string(),
+                    "basename" => Path::new(file)
+                        .file_name()
+                        .map_or(file.to_string(), |name| name.to_string_lossy().to_string()),
+                    name => captures
+                        .as_ref()
+                        .and_then(|captures| captures.name(name))
+                        .map_or("unknown", |value| value.as_str())
+                        .to_string(),
+                },
+            )
+            .to_string()
+    }
+
+    /// insert tag of file after log time and its closing characters, at start of log when log time
+    /// is not found, or as source field of JSON logs, prefixed by `_` when log has the field already
+    pub fn apply(&mut self, log: &str, file: &str, time_end: Option<usize>) -> String {
+        if let Some(mut object) = parse_json(log) {
+            let tag = self.tag(file).trim().to_string();
+            if let Some(fields) = object.as_object_mut() {
+                let mut field = SOURCE_FIELD.to_string();
+                while fields.contains_key(&field) {
+                    field.insert(0, '_');
+                }
+                fields.shift_insert(0, field, Value::String(tag));
+            }
+            let rest = log.find('\n').map_or("", |end| &log[end..]);
+            return format!("{}{}", object, rest);
+        }
+
+        let position = match time_end {
+            // skip rest of token like `]` of `[27/Sep/2021:01:00:00 +0800]` and the separator
+            Some(time_end) => log[time_end..]
+                .char_indices()
+                .find(|(_, c)| c.is_whitespace())
+                .map_or(log.len(), |(i, c)| time_end + i + c.len_utf8()),
+            None => 0,
+        };
+        let tag = self.tag(file).to_string();
+        format!("{}{}{}", &log[..position], tag, &log[position..])
+    }
+}
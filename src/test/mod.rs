@@ -1,37 +1,1832 @@
-use std::io::Result;
+use std::{
+    env,
+    error::Error,
+    fs::{self, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
 
+use bzip2::write::BzEncoder;
+use chrono::Datelike;
+use chrono_tz::Tz;
+use flate2::write::GzEncoder;
 use log::info;
+use xz2::write::XzEncoder;
 
+use super::dedup::{DedupKey, DedupOptions, Deduplicator};
+use super::errors::{ErrorPolicy, LogyError};
+use super::filter::FilterOptions;
+use super::follower::{self, FollowReader};
+use super::formats::{self, LogFormat};
+use super::models::{self, Codec, JsonFields, TimeRange, TimeZones, WriterOptions};
 use super::reducer;
+use super::stats::LatencyStats;
 use super::tracer;
+use super::trainer;
+
+type TestResult = Result<(), Box<dyn Error>>;
 
 #[test]
-fn test_reduce_log() -> Result<()> {
+fn test_reduce_log() -> TestResult {
     let files = vec!["/Users/nanashi07/Desktop/2021/09/big/real/source/app.2021-09-26.20.real-sports-game-7b88668458-vrlrw.log"];
 
     reducer::reduce_logs(
         &files,
-        r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#,
-        "%Y-%m-%d %H:%M:%S%.3f",
-        "/Users/nanashi07/Desktop/2021/09/big/real/tt/trace.output.log",
-        9,
+        &reducer::ReduceOptions {
+            writer_options: WriterOptions::new(9),
+            error_policy: ErrorPolicy::Warn,
+            ..reduce_options(Path::new(
+                "/Users/nanashi07/Desktop/2021/09/big/real/tt/trace.output.log",
+            ))
+        },
     )?;
     Ok(())
 }
 
 #[test]
-fn test_trace_log() -> Result<()> {
+fn test_trace_log() -> TestResult {
     let files = vec![
         "/Users/nanashi07/Desktop/2021/09/big/real/target/real-sports-game.20210927-01.log.gz",
     ];
     tracer::trace_log(
         &files,
-        4000,
-        r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#,
-        "%Y-%m-%d %H:%M:%S%.3f",
-        "real-sports-game-.+,(\\w+,\\w+)",
-        "/Users/nanashi07/Desktop/2021/09/big/real/trace.output.log",
+        &tracer::TraceOptions {
+            pattern: r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#.to_string(),
+            log_time_format: "%Y-%m-%d %H:%M:%S%.3f".to_string(),
+            trace_pattern: "real-sports-game-.+,(\\w+,\\w+)".to_string(),
+            output_file_pattern: "/Users/nanashi07/Desktop/2021/09/big/real/trace.output.log"
+                .to_string(),
+            min_cost_time: 4000,
+            idle_timeout: 300000,
+            memory_limit: 512 * 1024 * 1024,
+            error_policy: ErrorPolicy::Warn,
+            merge: false,
+            report_format: tracer::ReportFormat::Text,
+            stats: false,
+            stats_hourly: false,
+            start_pattern: None,
+            end_pattern: None,
+            bucket: None,
+            compress_level: 0,
+            codec: Codec::default(),
+            zstd_dictionary: None,
+            time_range: TimeRange::default(),
+            json: JsonFields::default(),
+            time_zones: TimeZones::default(),
+            tag_source: None,
+            source_pattern: None,
+        },
     )?;
     info!("task done");
     Ok(())
 }
+
+fn prepare_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("logy-{}-{}", name, process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn write_file(path: &Path, content: &str) -> String {
+    fs::write(path, content).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn test_reduce_log_by_log_time() -> TestResult {
+    let dir = prepare_dir("reduce-by-log-time");
+    let file_a = write_file(
+        &dir.join("a.log"),
+        "[WARN] 2021-09-27 01:00:00.100 same\n[INFO] 2021-09-27 01:00:00.300 a2\n",
+    );
+    let file_b = write_file(
+        &dir.join("b.log"),
+        "[WARN] 2021-09-27 01:00:00.100 same\n[ERROR] 2021-09-27 01:00:00.200 b1\n  at stack\n",
+    );
+    let output = dir.join("output.log");
+
+    reducer::reduce_logs(
+        &[file_a.as_str(), file_b.as_str()],
+        &reducer::ReduceOptions {
+            pattern: r#"^\[\w+\] (\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#.to_string(),
+            error_policy: ErrorPolicy::Warn,
+            ..reduce_options(&output)
+        },
+    )?;
+
+    assert_eq!(
+        fs::read_to_string(&output)?,
+        "[WARN] 2021-09-27 01:00:00.100 same\n\
+         [WARN] 2021-09-27 01:00:00.100 same\n\
+         [ERROR] 2021-09-27 01:00:00.200 b1\n  at stack\n\
+         [INFO] 2021-09-27 01:00:00.300 a2\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_reduce_compressed_log() -> TestResult {
+    let dir = prepare_dir("reduce-compressed");
+
+    let plain = write_file(&dir.join("plain.log"), "2021-09-27 01:00:00.500 plain\n");
+
+    // multi-member gzip, as produced by concatenating rotated archives
+    let mut gzip = Vec::new();
+    for line in &[
+        "2021-09-27 01:00:00.100 gzip\n",
+        "2021-09-27 01:00:00.600 gzip\n",
+    ] {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(line.as_bytes())?;
+        gzip.extend(encoder.finish()?);
+    }
+    let gzip_file = dir.join("gzip.log.gz");
+    fs::write(&gzip_file, gzip)?;
+
+    let zstd_file = dir.join("zstd.log.zst");
+    fs::write(
+        &zstd_file,
+        zstd::encode_all("2021-09-27 01:00:00.200 zstd\n".as_bytes(), 3)?,
+    )?;
+
+    let mut bzip2 = BzEncoder::new(Vec::new(), bzip2::Compression::default());
+    bzip2.write_all(b"2021-09-27 01:00:00.300 bzip2\n")?;
+    let bzip2_file = dir.join("bzip2.log.bz2");
+    fs::write(&bzip2_file, bzip2.finish()?)?;
+
+    let mut xz = XzEncoder::new(Vec::new(), 6);
+    xz.write_all(b"2021-09-27 01:00:00.400 xz\n")?;
+    let xz_file = dir.join("xz.log.xz");
+    fs::write(&xz_file, xz.finish()?)?;
+
+    let output = dir.join("output.log");
+    reducer::reduce_logs(
+        &[
+            plain.as_str(),
+            gzip_file.to_str().unwrap(),
+            zstd_file.to_str().unwrap(),
+            bzip2_file.to_str().unwrap(),
+            xz_file.to_str().unwrap(),
+        ],
+        &reducer::ReduceOptions {
+            error_policy: ErrorPolicy::Warn,
+            ..reduce_options(&output)
+        },
+    )?;
+
+    assert_eq!(
+        fs::read_to_string(&output)?,
+        "2021-09-27 01:00:00.100 gzip\n\
+         2021-09-27 01:00:00.200 zstd\n\
+         2021-09-27 01:00:00.300 bzip2\n\
+         2021-09-27 01:00:00.400 xz\n\
+         2021-09-27 01:00:00.500 plain\n\
+         2021-09-27 01:00:00.600 gzip\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_reduce_malformed_log() -> TestResult {
+    let dir = prepare_dir("reduce-malformed");
+    let file = write_file(
+        &dir.join("app.log"),
+        "2021-09-27 01:00:00.100 first\n2021-13-27 01:00:00.200 bad month\n\
+         2021-09-32 01:00:00.200 bad day\n2021-09-27 01:00:00.300 last\n",
+    );
+    let output = dir.join("output.log");
+
+    // file given twice is merged twice
+    reducer::reduce_logs(
+        &[file.as_str(), file.as_str()],
+        &reducer::ReduceOptions {
+            error_policy: ErrorPolicy::Skip,
+            ..reduce_options(&output)
+        },
+    )?;
+    assert_eq!(
+        fs::read_to_string(&output)?,
+        "2021-09-27 01:00:00.100 first\n2021-09-27 01:00:00.100 first\n\
+         2021-09-27 01:00:00.300 last\n2021-09-27 01:00:00.300 last\n"
+    );
+
+    // malformed log aborts in merge order, after logs merged before it
+    let other_file = write_file(
+        &dir.join("other.log"),
+        "2021-09-27 01:00:00.000 other first\n2021-09-27 01:00:00.250 other last\n",
+    );
+    let result = reducer::reduce_logs(
+        &[file.as_str(), other_file.as_str()],
+        &reduce_options(&dir.join("aborted.log")),
+    );
+    match result {
+        Err(LogyError::MalformedLog { line_number, .. }) => assert_eq!(line_number, 2),
+        _ => panic!("malformed log should abort reduce"),
+    }
+    assert_eq!(
+        fs::read_to_string(dir.join("aborted.log"))?,
+        "2021-09-27 01:00:00.000 other first\n2021-09-27 01:00:00.100 first\n"
+    );
+
+    let result = reducer::reduce_logs(
+        &[dir.join("missing.log").to_str().unwrap()],
+        &reduce_options(&dir.join("missing.output.log")),
+    );
+    assert!(matches!(result, Err(LogyError::Io { .. })));
+    Ok(())
+}
+
+fn reduce_options(output: &Path) -> reducer::ReduceOptions {
+    reducer::ReduceOptions {
+        pattern: r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#.to_string(),
+        log_time_format: "%Y-%m-%d %H:%M:%S%.3f".to_string(),
+        output_file_pattern: output.to_str().unwrap().to_string(),
+        writer_options: WriterOptions::new(0),
+        error_policy: ErrorPolicy::Abort,
+        time_range: TimeRange::default(),
+        filter: FilterOptions::default(),
+        json: JsonFields::default(),
+        time_zones: TimeZones::default(),
+        out_time_zone: None,
+        annotate_time: false,
+        tag_source: None,
+        source_pattern: None,
+        dedup: None,
+        sort_memory: None,
+        threads: None,
+        zstd_dictionary: None,
+    }
+}
+
+fn trace_options(output: &Path, idle_timeout: i64, memory_limit: usize) -> tracer::TraceOptions {
+    tracer::TraceOptions {
+        pattern: r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#.to_string(),
+        log_time_format: "%Y-%m-%d %H:%M:%S%.3f".to_string(),
+        trace_pattern: r#"\[(\w+)\]"#.to_string(),
+        output_file_pattern: output.to_str().unwrap().to_string(),
+        min_cost_time: 5000,
+        idle_timeout,
+        memory_limit,
+        error_policy: ErrorPolicy::Warn,
+        merge: false,
+        report_format: tracer::ReportFormat::Text,
+        stats: false,
+        stats_hourly: false,
+        start_pattern: None,
+        end_pattern: None,
+        bucket: None,
+        compress_level: 0,
+        codec: Codec::default(),
+        zstd_dictionary: None,
+        time_range: TimeRange::default(),
+        json: JsonFields::default(),
+        time_zones: TimeZones::default(),
+        tag_source: None,
+        source_pattern: None,
+    }
+}
+
+const TRACE_LOGS: &str = "2021-09-27 01:00:00.000 [t1] start\n\
+                          2021-09-27 01:00:01.000 [t2] start\n\
+                          2021-09-27 01:00:02.000 [t2] end\n\
+                          2021-09-27 01:00:03.000 [t3] start\n\
+                          2021-09-27 01:00:09.000 [t1] end\n  at stack\n\
+                          2021-09-27 01:00:10.000 no trace\n\
+                          2021-09-27 01:00:10.000 [t3] end\n";
+
+#[test]
+fn test_reduce_log_with_rotation() -> TestResult {
+    let dir = prepare_dir("reduce-rotation");
+    let file = write_file(
+        &dir.join("a.log"),
+        "2021-09-27 01:00:00.100 a1\n\
+         2021-09-27 01:00:00.200 a2\n  at stack\n\
+         2021-09-27 01:00:00.300 a3\n\
+         2021-09-27 01:00:00.400 a4\n\
+         2021-09-27 01:00:00.500 a5\n\
+         2021-09-27 02:00:00.100 b1\n",
+    );
+
+    reducer::reduce_logs(
+        &[file.as_str()],
+        &reducer::ReduceOptions {
+            writer_options: WriterOptions {
+                max_lines: Some(2),
+                ..WriterOptions::new(0)
+            },
+            ..reduce_options(&dir.join("out/output.%Y%m%d-%H.log"))
+        },
+    )?;
+    let read = |name: &str| fs::read_to_string(dir.join("out").join(name)).unwrap();
+    assert_eq!(
+        read("output.20210927-01.001.log"),
+        "2021-09-27 01:00:00.100 a1\n2021-09-27 01:00:00.200 a2\n  at stack\n"
+    );
+    assert_eq!(
+        read("output.20210927-01.002.log"),
+        "2021-09-27 01:00:00.300 a3\n2021-09-27 01:00:00.400 a4\n"
+    );
+    assert_eq!(
+        read("output.20210927-01.003.log"),
+        "2021-09-27 01:00:00.500 a5\n"
+    );
+    assert_eq!(
+        read("output.20210927-02.001.log"),
+        "2021-09-27 02:00:00.100 b1\n"
+    );
+
+    // only latest rolled files are kept besides current one
+    reducer::reduce_logs(
+        &[file.as_str()],
+        &reducer::ReduceOptions {
+            writer_options: WriterOptions {
+                max_size: Some(20),
+                retention: Some(2),
+                ..WriterOptions::new(0)
+            },
+            ..reduce_options(&dir.join("retained/output.%Y%m%d-%H.log"))
+        },
+    )?;
+    let mut files = fs::read_dir(dir.join("retained"))?
+        .map(|entry| entry.map(|entry| entry.file_name().into_string().unwrap()))
+        .collect::<Result<Vec<String>, _>>()?;
+    files.sort();
+    assert_eq!(
+        files,
+        vec![
+            "output.20210927-01.004.log",
+            "output.20210927-01.005.log",
+            "output.20210927-02.001.log",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_write_parallel_compressed_log() -> TestResult {
+    let dir = prepare_dir("write-parallel-gzip");
+    let decode = |name: &str| -> Result<String, Box<dyn Error>> {
+        let mut content = String::new();
+        flate2::read::MultiGzDecoder::new(fs::File::open(dir.join(name))?)
+            .read_to_string(&mut content)?;
+        Ok(content)
+    };
+    let write = |name: &str, lines: &[String], threads: usize| -> Result<(), LogyError> {
+        let mut writer = models::WrappedFileWriter::new(
+            dir.join(name).to_str().unwrap(),
+            WriterOptions {
+                compress_threads: Some(threads),
+                ..WriterOptions::new(9)
+            },
+        )?;
+        for (i, line) in lines.iter().enumerate() {
+            writer.write(i as i64, line)?;
+        }
+        writer.flush()
+    };
+    // a few blocks of input, last one is partial
+    let lines = (0..60000)
+        .map(|i| format!("2021-09-27 01:00:00.000 request {} done", i))
+        .collect::<Vec<String>>();
+    let expected = lines
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect::<String>();
+    assert!(expected.len() > 2 * 1024 * 1024);
+
+    write("parallel.log", &lines, 4)?;
+    assert_eq!(decode("parallel.log.gz")?, expected);
+    // blocks are separate members
+    let mut first_member = String::new();
+    flate2::read::GzDecoder::new(fs::File::open(dir.join("parallel.log.gz"))?)
+        .read_to_string(&mut first_member)?;
+    assert_eq!(first_member.len(), 1024 * 1024);
+
+    write("single.log", &lines, 1)?;
+    assert_eq!(decode("single.log.gz")?, expected);
+
+    // empty file is still valid gzip
+    write("empty.log", &[], 4)?;
+    assert_eq!(decode("empty.log.gz")?, "");
+
+    // input held by encoder counts toward file size
+    let max_size = 256 * 1024;
+    let mut writer = models::WrappedFileWriter::new(
+        dir.join("rolled.log").to_str().unwrap(),
+        WriterOptions {
+            compress_threads: Some(2),
+            max_size: Some(max_size),
+            ..WriterOptions::new(9)
+        },
+    )?;
+    for (i, line) in lines.iter().enumerate() {
+        writer.write(i as i64, line)?;
+    }
+    writer.flush()?;
+    drop(writer);
+    let mut rolled = String::new();
+    for index in 1.. {
+        let name = format!("rolled.{:03}.log.gz", index);
+        if !dir.join(&name).exists() {
+            assert!(index > 2, "{} files rolled", index - 1);
+            break;
+        }
+        let content = decode(&name)?;
+        assert!(content.len() as u64 <= max_size + lines[0].len() as u64 + 8);
+        rolled.push_str(&content);
+    }
+    assert_eq!(rolled, expected);
+    Ok(())
+}
+
+#[test]
+fn test_write_log_with_codecs() -> TestResult {
+    let dir = prepare_dir("write-codecs");
+    let write = |name: &str, lines: &[String], options: WriterOptions| -> Result<(), LogyError> {
+        let mut writer = models::WrappedFileWriter::new(dir.join(name).to_str().unwrap(), options)?;
+        for (i, line) in lines.iter().enumerate() {
+            writer.write(i as i64, line)?;
+        }
+        writer.flush()
+    };
+    let lines = (0..1000)
+        .map(|i| format!("2021-09-27 01:00:00.000 request {} done", i))
+        .collect::<Vec<String>>();
+    let expected = lines
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect::<String>();
+
+    for (codec, extension) in [
+        (Codec::Gzip, ".gz"),
+        (Codec::Zstd, ".zst"),
+        (Codec::Bzip2, ".bz2"),
+        (Codec::Xz, ".xz"),
+    ] {
+        write(
+            "codec.log",
+            &lines,
+            WriterOptions {
+                codec,
+                ..WriterOptions::new(9)
+            },
+        )?;
+        let file = format!("codec.log{}", extension);
+        let mut content = String::new();
+        models::open_source(dir.join(&file).to_str().unwrap(), None)?
+            .read_to_string(&mut content)?;
+        assert_eq!(content, expected, "{}", file);
+    }
+    // level 0 writes plain text whatever the codec is
+    write(
+        "plain.log",
+        &lines,
+        WriterOptions {
+            codec: Codec::Zstd,
+            ..WriterOptions::new(0)
+        },
+    )?;
+    assert_eq!(fs::read_to_string(dir.join("plain.log"))?, expected);
+
+    // small file compressed with dictionary trained from similar logs
+    let samples = write_file(
+        &dir.join("samples.log"),
+        &(0..5000)
+            .map(|i| {
+                format!(
+                    "2021-09-27 01:00:{:02}.000 trace-{} request {} done\n",
+                    i % 60,
+                    i,
+                    i
+                )
+            })
+            .collect::<String>(),
+    );
+    let dictionary = trainer::train_dictionary(
+        &[samples.as_str()],
+        r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#,
+        16 * 1024,
+    )?;
+    assert!(!dictionary.is_empty() && dictionary.len() <= 16 * 1024);
+    let small = &lines[..3];
+    write(
+        "dict.log",
+        small,
+        WriterOptions {
+            codec: Codec::Zstd,
+            zstd_dictionary: Some(Arc::new(dictionary.clone())),
+            ..WriterOptions::new(19)
+        },
+    )?;
+    let mut content = String::new();
+    zstd::stream::read::Decoder::with_dictionary(
+        std::io::BufReader::new(fs::File::open(dir.join("dict.log.zst"))?),
+        &dictionary,
+    )?
+    .read_to_string(&mut content)?;
+    assert_eq!(
+        content,
+        small
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect::<String>()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_reduce_log_with_zstd_dictionary() -> TestResult {
+    let dir = prepare_dir("reduce-zstd-dictionary");
+    let prefix = r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#;
+    let samples = write_file(
+        &dir.join("samples.log"),
+        &(0..5000)
+            .map(|i| format!("2021-09-27 01:00:{:02}.000 [t{}] request done\n", i % 60, i))
+            .collect::<String>(),
+    );
+    let dictionary = Arc::new(trainer::train_dictionary(
+        &[samples.as_str()],
+        prefix,
+        16 * 1024,
+    )?);
+    let content = "2021-09-27 01:00:00.100 [t1] request done\n\
+                   2021-09-27 01:00:00.200 [t2] request done\n";
+    let file = write_file(&dir.join("a.log"), content);
+    let reduce = |file: &str, output: &str, writer_options: WriterOptions, dictionary| {
+        reducer::reduce_logs(
+            &[file],
+            &reducer::ReduceOptions {
+                writer_options,
+                zstd_dictionary: dictionary,
+                ..reduce_options(&dir.join(output))
+            },
+        )
+    };
+    let zstd = |dictionary: Option<Arc<Vec<u8>>>| WriterOptions {
+        codec: Codec::Zstd,
+        zstd_dictionary: dictionary,
+        ..WriterOptions::new(19)
+    };
+
+    // output compressed with dictionary is read back with it
+    reduce(&file, "dict.log", zstd(Some(dictionary.clone())), None)?;
+    let compressed = dir.join("dict.log.zst");
+    let compressed = compressed.to_str().unwrap();
+    assert!(reduce(compressed, "failed.log", WriterOptions::new(0), None).is_err());
+    reduce(
+        compressed,
+        "restored.log",
+        WriterOptions::new(0),
+        Some(dictionary.clone()),
+    )?;
+    assert_eq!(fs::read_to_string(dir.join("restored.log"))?, content);
+
+    // zstd files without dictionary are still read when dictionary is given
+    reduce(&file, "plain.log", zstd(None), None)?;
+    let plain = dir.join("plain.log.zst");
+    reduce(
+        plain.to_str().unwrap(),
+        "plain-restored.log",
+        WriterOptions::new(0),
+        Some(dictionary),
+    )?;
+    assert_eq!(fs::read_to_string(dir.join("plain-restored.log"))?, content);
+    Ok(())
+}
+
+#[test]
+fn test_reject_invalid_bucket() {
+    let bucket = |value: &str| {
+        let matches = super::command_args()
+            .get_matches_from(vec!["logy", "reduce", "--bucket", value, "a.log"]);
+        let args = matches.subcommand_matches("reduce").unwrap();
+        super::optional_arg(args, "bucket", super::parse_bucket)
+    };
+    assert_eq!(bucket("15m").unwrap(), Some(15 * 60 * 1000));
+    for value in ["0s", "0ms", "99999999999999999w", "15"] {
+        match bucket(value) {
+            Err(LogyError::Argument { name, .. }) => assert_eq!(name, "bucket"),
+            result => panic!("{} is accepted as {:?}", value, result),
+        }
+    }
+}
+
+#[test]
+fn test_reject_invalid_max_memory() {
+    let max_memory = |value: &str| {
+        let matches = super::command_args().get_matches_from(vec![
+            "logy",
+            "trace",
+            "-g",
+            "(t)",
+            "--max-memory",
+            value,
+            "a.log",
+        ]);
+        let args = matches.subcommand_matches("trace").unwrap();
+        super::optional_arg(args, "max-memory", super::parse_megabytes)
+    };
+    assert_eq!(max_memory("2").unwrap(), Some(2 * 1024 * 1024));
+    for value in ["99999999999999999", "1.5", "1G"] {
+        match max_memory(value) {
+            Err(LogyError::Argument { name, .. }) => assert_eq!(name, "max-memory"),
+            result => panic!("{} is accepted as {:?}", value, result),
+        }
+    }
+}
+
+#[test]
+fn test_reject_invalid_size() {
+    let max_size = |value: &str| {
+        let matches = super::command_args().get_matches_from(vec![
+            "logy",
+            "reduce",
+            "--max-size",
+            value,
+            "a.log",
+        ]);
+        let args = matches.subcommand_matches("reduce").unwrap();
+        super::optional_arg(args, "max-size", super::parse_size)
+    };
+    assert_eq!(max_size("2K").unwrap(), Some(2048));
+    for value in ["99999999999999999G", "18446744073709551616", "1T"] {
+        match max_size(value) {
+            Err(LogyError::Argument { name, .. }) => assert_eq!(name, "max-size"),
+            result => panic!("{} is accepted as {:?}", value, result),
+        }
+    }
+}
+
+#[test]
+fn test_reduce_log_by_time_bucket() -> TestResult {
+    let dir = prepare_dir("reduce-time-bucket");
+    let file = write_file(
+        &dir.join("a.log"),
+        "2021-09-26 23:59:59.999 a1\n\
+         2021-09-27 01:07:00.000 a2\n\
+         2021-09-27 01:14:59.999 a3\n\
+         2021-09-27 01:15:00.000 a4\n",
+    );
+    let reduce = |pattern: &str, bucket: Option<i64>| {
+        reducer::reduce_logs(
+            &[file.as_str()],
+            &reducer::ReduceOptions {
+                writer_options: WriterOptions {
+                    bucket,
+                    ..WriterOptions::new(0)
+                },
+                ..reduce_options(&dir.join(pattern))
+            },
+        )
+    };
+    let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+
+    reduce("quarter/%Y%m%d-%H%M.log", Some(15 * 60 * 1000))?;
+    assert_eq!(
+        read("quarter/20210926-2345.log"),
+        "2021-09-26 23:59:59.999 a1\n"
+    );
+    assert_eq!(
+        read("quarter/20210927-0100.log"),
+        "2021-09-27 01:07:00.000 a2\n2021-09-27 01:14:59.999 a3\n"
+    );
+    assert_eq!(
+        read("quarter/20210927-0115.log"),
+        "2021-09-27 01:15:00.000 a4\n"
+    );
+
+    // bucket follows finest time specifier of pattern
+    reduce("minute/%Y%m%d-%H%M.log", None)?;
+    assert_eq!(
+        read("minute/20210927-0114.log"),
+        "2021-09-27 01:14:59.999 a3\n"
+    );
+    reduce("daily/%Y%m%d.log", None)?;
+    assert_eq!(read("daily/20210926.log"), "2021-09-26 23:59:59.999 a1\n");
+
+    // weeks start on Monday, 2021-09-27 is Monday
+    reduce("weekly/%Y%m%d.log", Some(7 * 24 * 3600 * 1000))?;
+    assert_eq!(read("weekly/20210920.log"), "2021-09-26 23:59:59.999 a1\n");
+    assert_eq!(fs::read_dir(dir.join("weekly"))?.count(), 2);
+    Ok(())
+}
+
+#[test]
+fn test_reduce_log_by_partition() -> TestResult {
+    let dir = prepare_dir("reduce-partition");
+    let file = write_file(
+        &dir.join("a.log"),
+        "2021-09-27 01:00:00.100 [api] INFO a1\n\
+         2021-09-27 01:00:00.200 [web] WARN b1\n  at stack\n\
+         2021-09-27 01:00:00.300 [api] WARN a2\n\
+         2021-09-27 01:00:00.400 [api] INFO a3\n\
+         2021-09-27 01:00:00.500 no service\n",
+    );
+    let partition_by = r"\[(?P<service>\w+)\] (?P<level>\w+)";
+
+    // single open file forces every switch to close and reopen files
+    reducer::reduce_logs(
+        &[file.as_str()],
+        &reducer::ReduceOptions {
+            writer_options: WriterOptions {
+                partition_by: Some(partition_by.to_string()),
+                max_open_files: Some(1),
+                ..WriterOptions::new(0)
+            },
+            ..reduce_options(&dir.join("out/{service}/{level}.%Y%m%d.log"))
+        },
+    )?;
+    let read = |name: &str| fs::read_to_string(dir.join("out").join(name)).unwrap();
+    assert_eq!(
+        read("api/INFO.20210927.log"),
+        "2021-09-27 01:00:00.100 [api] INFO a1\n2021-09-27 01:00:00.400 [api] INFO a3\n"
+    );
+    assert_eq!(
+        read("api/WARN.20210927.log"),
+        "2021-09-27 01:00:00.300 [api] WARN a2\n"
+    );
+    assert_eq!(
+        read("web/WARN.20210927.log"),
+        "2021-09-27 01:00:00.200 [web] WARN b1\n  at stack\n"
+    );
+    assert_eq!(
+        read("unknown/unknown.20210927.log"),
+        "2021-09-27 01:00:00.500 no service\n"
+    );
+
+    // captured values can not leave their directory level
+    let dots = write_file(
+        &dir.join("dots.log"),
+        "2021-09-27 01:00:00.100 [..] INFO up\n\
+         2021-09-27 01:00:00.200 [.] INFO same\n\
+         2021-09-27 01:00:00.300 [] INFO empty\n",
+    );
+    reducer::reduce_logs(
+        &[dots.as_str()],
+        &reducer::ReduceOptions {
+            writer_options: WriterOptions {
+                partition_by: Some(r"\[(?P<service>[^\]]*)\] (?P<level>\w+)".to_string()),
+                ..WriterOptions::new(0)
+            },
+            ..reduce_options(&dir.join("dots/{service}/{level}.log"))
+        },
+    )?;
+    let read = |name: &str| fs::read_to_string(dir.join("dots").join(name)).unwrap();
+    assert_eq!(
+        read("__/INFO.log"),
+        "2021-09-27 01:00:00.100 [..] INFO up\n"
+    );
+    assert_eq!(
+        read("_/INFO.log"),
+        "2021-09-27 01:00:00.200 [.] INFO same\n"
+    );
+    assert_eq!(
+        read("unknown/INFO.log"),
+        "2021-09-27 01:00:00.300 [] INFO empty\n"
+    );
+    assert!(!dir.join("INFO.log").exists() && !dir.join("dots/INFO.log").exists());
+
+    let result = reducer::reduce_logs(
+        &[file.as_str()],
+        &reducer::ReduceOptions {
+            writer_options: WriterOptions {
+                partition_by: Some(partition_by.to_string()),
+                ..WriterOptions::new(0)
+            },
+            ..reduce_options(&dir.join("{tenant}.log"))
+        },
+    );
+    assert!(matches!(result, Err(LogyError::Argument { .. })));
+    Ok(())
+}
+
+#[test]
+fn test_reduce_log_in_time_range() -> TestResult {
+    let dir = prepare_dir("reduce-time-range");
+    let file = write_file(
+        &dir.join("a.log"),
+        "2021-09-27 00:59:59.999 before\n\
+         2021-09-27 01:00:00.000 since\n  at stack\n\
+         2021-09-27 01:09:59.999 inside\n\
+         2021-09-27 01:10:00.000 until\n\
+         2021-09-27 01:05:00.000 late\n",
+    );
+    let reduce = |output: &Path, sorted: bool| {
+        reducer::reduce_logs(
+            &[file.as_str()],
+            &reducer::ReduceOptions {
+                time_range: TimeRange {
+                    since: Some(models::parse_time("2021-09-27 01:00", &Tz::UTC)?),
+                    until: Some(models::parse_time("2021-09-27T01:10:00", &Tz::UTC)?),
+                    sorted,
+                },
+                ..reduce_options(output)
+            },
+        )
+        .map_err(|e| e.to_string())
+    };
+
+    reduce(&dir.join("all.log"), false)?;
+    assert_eq!(
+        fs::read_to_string(dir.join("all.log"))?,
+        "2021-09-27 01:00:00.000 since\n  at stack\n\
+         2021-09-27 01:09:59.999 inside\n\
+         2021-09-27 01:05:00.000 late\n"
+    );
+
+    // reading stops at first log out of range
+    reduce(&dir.join("sorted.log"), true)?;
+    assert_eq!(
+        fs::read_to_string(dir.join("sorted.log"))?,
+        "2021-09-27 01:00:00.000 since\n  at stack\n\
+         2021-09-27 01:09:59.999 inside\n"
+    );
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let two_hours_ago = models::parse_time("-2h", &Tz::UTC)?;
+    assert!((now - 2 * 3600 * 1000 - two_hours_ago).abs() < 60 * 1000);
+    assert!(models::parse_time("yesterday", &Tz::UTC).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_reduce_log_in_time_zones() -> TestResult {
+    let dir = prepare_dir("reduce-time-zones");
+    let prefix = r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#;
+    let utc_file = write_file(
+        &dir.join("utc.log"),
+        "2021-09-27 01:00:00.000 utc1\n\
+         2021-09-27 02:00:00.000 utc2\n",
+    );
+    let taipei_file = write_file(&dir.join("taipei.log"), "2021-09-27 09:30:00.000 tpe1\n");
+    let time_zones = TimeZones::parse(&[&format!("{}=Asia/Taipei", taipei_file)])?;
+    let reduce = |pattern: &str, out_time_zone: Option<Tz>, annotate_time: bool| {
+        reducer::reduce_logs(
+            &[utc_file.as_str(), taipei_file.as_str()],
+            &reducer::ReduceOptions {
+                writer_options: WriterOptions {
+                    time_zone: out_time_zone,
+                    ..WriterOptions::new(0)
+                },
+                time_zones: time_zones.clone(),
+                out_time_zone,
+                annotate_time,
+                ..reduce_options(&dir.join(pattern))
+            },
+        )
+    };
+    let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+
+    // merged by instants, hours of output files are of output time zone
+    reduce("taipei/%Y%m%d-%H.log", Some(Tz::Asia__Taipei), false)?;
+    assert_eq!(
+        read("taipei/20210927-09.log"),
+        "2021-09-27 09:00:00.000 utc1\n2021-09-27 09:30:00.000 tpe1\n"
+    );
+    assert_eq!(
+        read("taipei/20210927-10.log"),
+        "2021-09-27 10:00:00.000 utc2\n"
+    );
+
+    reduce("utc/%Y%m%d.log", Some(Tz::UTC), true)?;
+    assert_eq!(
+        read("utc/20210927.log"),
+        "[2021-09-27T01:00:00.000+00:00] 2021-09-27 01:00:00.000 utc1\n\
+         [2021-09-27T01:30:00.000+00:00] 2021-09-27 09:30:00.000 tpe1\n\
+         [2021-09-27T02:00:00.000+00:00] 2021-09-27 02:00:00.000 utc2\n"
+    );
+
+    // JSON logs stay in JSON Lines, time field is rewritten or local time field is added
+    let json_file = write_file(
+        &dir.join("json.log"),
+        "{\"time\":1632704400000,\"msg\":\"json1\"}\n",
+    );
+    let json_parser = models::LogTimeParser::new(prefix, "%Y-%m-%d %H:%M:%S%.3f")?
+        .with_json_fields(&JsonFields {
+            time: Some("time".to_string()),
+            ..JsonFields::default()
+        });
+    let json_log = fs::read_to_string(&json_file)?;
+    let json_log = json_log.trim_end();
+    assert_eq!(
+        json_parser.localize(json_log, 1632704400000, &Tz::Asia__Taipei, false),
+        r#"{"time":"2021-09-27T09:00:00.000+08:00","msg":"json1"}"#
+    );
+    assert_eq!(
+        json_parser.localize(json_log, 1632704400000, &Tz::Asia__Taipei, true),
+        r#"{"local_time":"2021-09-27T09:00:00.000+08:00","time":1632704400000,"msg":"json1"}"#
+    );
+
+    // offset of log time takes precedence over time zone
+    let parser = models::LogTimeParser::new(
+        r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3}[+-]\d{4})"#,
+        "%Y-%m-%d %H:%M:%S%.3f%z",
+    )?
+    .with_time_zones(&time_zones);
+    assert_eq!(
+        parser.parse_file_log(&taipei_file, "2021-09-27 09:30:00.000+0800 tpe1"),
+        models::parse_time("2021-09-27 01:30", &Tz::UTC)
+    );
+    assert!(TimeZones::parse(&["Mars/Olympus"]).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_reduce_log_with_source_tags() -> TestResult {
+    let dir = prepare_dir("reduce-source-tags");
+    let api_file = write_file(
+        &dir.join("api-7d9f.log"),
+        "2021-09-27 01:00:00.000 [t1] api1\n  at stack\n\
+         2021-09-27 01:00:07.000 [t1] api2\n",
+    );
+    let web_file = write_file(
+        &dir.join("web-5c2a.log"),
+        "2021-09-27 01:00:01.000 [t1] web1\n\
+         {\"time\":\"2021-09-27 01:00:02.000\",\"msg\":\"web2\"}\n\
+         {\"source\":\"nginx\",\"time\":\"2021-09-27 01:00:03.000\",\"msg\":\"web3\"}\n",
+    );
+    let files = [api_file.as_str(), web_file.as_str()];
+    let reduce = |output: &str, tag_source: &str, source_pattern: Option<&str>| {
+        reducer::reduce_logs(
+            &files,
+            &reducer::ReduceOptions {
+                json: JsonFields {
+                    time: Some("time".to_string()),
+                    ..JsonFields::default()
+                },
+                tag_source: Some(tag_source.to_string()),
+                source_pattern: source_pattern.map(|s| s.to_string()),
+                ..reduce_options(&dir.join(output))
+            },
+        )
+    };
+
+    reduce("basename.log", "[{basename}] ", None)?;
+    assert_eq!(
+        fs::read_to_string(dir.join("basename.log"))?,
+        "2021-09-27 01:00:00.000 [api-7d9f.log] [t1] api1\n  at stack\n\
+         2021-09-27 01:00:01.000 [web-5c2a.log] [t1] web1\n\
+         {\"source\":\"[web-5c2a.log]\",\"time\":\"2021-09-27 01:00:02.000\",\"msg\":\"web2\"}\n\
+         {\"_source\":\"[web-5c2a.log]\",\"source\":\"nginx\",\"time\":\"2021-09-27 01:00:03.000\",\"msg\":\"web3\"}\n\
+         2021-09-27 01:00:07.000 [api-7d9f.log] [t1] api2\n"
+    );
+
+    reduce("pod.log", "{pod} ", Some(r"(?P<pod>[a-z]+)-\w+\.log$"))?;
+    assert!(fs::read_to_string(dir.join("pod.log"))?
+        .starts_with("2021-09-27 01:00:00.000 api [t1] api1\n"));
+    assert!(reduce("unknown.log", "{host} ", None).is_err());
+
+    // tracer reports which sources a trace touched
+    let output = dir.join("trace.json");
+    let mut options = trace_options(&output, 300000, 1024);
+    options.merge = true;
+    options.report_format = tracer::ReportFormat::Json;
+    options.tag_source = Some("{pod}".to_string());
+    options.source_pattern = Some(r"(?P<pod>[a-z]+)-\w+\.log$".to_string());
+    tracer::trace_log(&files, &options)?;
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&output)?)?;
+    assert_eq!(report["sources"], serde_json::json!(["api", "web"]));
+    assert_eq!(report["first_line"], "2021-09-27 01:00:00.000 api[t1] api1");
+
+    // merged logs are tagged by template only
+    let output = dir.join("trace.log");
+    options.output_file_pattern = output.to_str().unwrap().to_string();
+    options.report_format = tracer::ReportFormat::Text;
+    options.tag_source = Some("[{basename}] ".to_string());
+    options.source_pattern = None;
+    tracer::trace_log(&files, &options)?;
+    assert!(fs::read_to_string(&output)?
+        .contains("\n2021-09-27 01:00:00.000 [api-7d9f.log] [t1] api1\n"));
+    Ok(())
+}
+
+#[test]
+fn test_reduce_log_with_dedup() -> TestResult {
+    let dir = prepare_dir("reduce-dedup");
+    let logs = "2021-09-27 01:00:00.000 id=1 start\n  at stack\n\
+                2021-09-27 01:00:00.000 id=1 start\n  at stack\n\
+                2021-09-27 01:00:01.000 id=2 retry\n\
+                2021-09-27 01:00:03.000 id=2 retry\n";
+    let first = write_file(&dir.join("sidecar-a.log"), logs);
+    let second = write_file(&dir.join("sidecar-b.log"), logs);
+    let reduce = |output: &Path, key: Option<DedupKey>, window: i64| {
+        reducer::reduce_logs(
+            &[first.as_str(), second.as_str()],
+            &reducer::ReduceOptions {
+                dedup: key.map(|key| DedupOptions { key, window }),
+                ..reduce_options(output)
+            },
+        )
+    };
+    let read = |output: &Path| fs::read_to_string(output).unwrap().lines().count();
+
+    // identical logs of different files are all kept without dedup
+    reduce(&dir.join("all.log"), None, 0)?;
+    assert_eq!(read(&dir.join("all.log")), 12);
+
+    reduce(&dir.join("entry.log"), Some(DedupKey::Entry), 1000)?;
+    assert_eq!(
+        fs::read_to_string(dir.join("entry.log"))?,
+        "2021-09-27 01:00:00.000 id=1 start\n  at stack\n\
+         2021-09-27 01:00:01.000 id=2 retry\n\
+         2021-09-27 01:00:03.000 id=2 retry\n"
+    );
+
+    reduce(&dir.join("source.log"), Some(DedupKey::EntrySource), 1000)?;
+    assert_eq!(read(&dir.join("source.log")), 8);
+
+    reduce(
+        &dir.join("capture.log"),
+        Some(DedupKey::Capture(r"id=(?P<key>\d+)".to_string())),
+        5000,
+    )?;
+    assert_eq!(
+        fs::read_to_string(dir.join("capture.log"))?,
+        "2021-09-27 01:00:00.000 id=1 start\n  at stack\n\
+         2021-09-27 01:00:01.000 id=2 retry\n"
+    );
+
+    let mut deduplicator = Deduplicator::new(&DedupOptions {
+        key: DedupKey::Entry,
+        window: 1000,
+    })?;
+    assert!(deduplicator.accept(0, "a.log", "log"));
+    assert!(!deduplicator.accept(1000, "b.log", "log"));
+    assert!(deduplicator.accept(1001, "b.log", "log"));
+    assert_eq!(deduplicator.removed(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_reduce_unsorted_log() -> TestResult {
+    let dir = prepare_dir("reduce-unsorted");
+    let count = 500;
+    let log_of = |i: i64| {
+        format!(
+            "2021-09-27 01:{:02}:{:02}.000 log{}\n  at stack{}\n",
+            i / 60,
+            i % 60,
+            i,
+            i
+        )
+    };
+    // shuffled by a permutation, second file is roughly ordered
+    let first = write_file(
+        &dir.join("a.log"),
+        &(0..count)
+            .map(|i| log_of(i * 7 % count * 2))
+            .collect::<String>(),
+    );
+    let second = write_file(
+        &dir.join("b.log"),
+        &(0..count)
+            .map(|i| log_of((i ^ 1) * 2 + 1))
+            .collect::<String>(),
+    );
+    let reduce = |output: &Path, sort_memory: usize| {
+        reducer::reduce_logs(
+            &[first.as_str(), second.as_str()],
+            &reducer::ReduceOptions {
+                sort_memory: Some(sort_memory),
+                ..reduce_options(output)
+            },
+        )
+    };
+    let expected = (0..count * 2).map(log_of).collect::<String>();
+
+    // sorted in memory
+    reduce(&dir.join("memory.log"), 1024 * 1024)?;
+    assert_eq!(fs::read_to_string(dir.join("memory.log"))?, expected);
+
+    // runs of a few logs are more than merged at once
+    reduce(&dir.join("spilled.log"), 300)?;
+    assert_eq!(fs::read_to_string(dir.join("spilled.log"))?, expected);
+    Ok(())
+}
+
+/// throughput of reduce with one reader and with the pool, run with `--ignored --nocapture` to see
+/// numbers
+#[test]
+#[ignore]
+fn test_reduce_throughput() -> TestResult {
+    let dir = prepare_dir("reduce-throughput");
+    let (file_count, log_count) = (16, 5000);
+    let mut files = Vec::new();
+    for f in 0..file_count {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        for i in 0..log_count {
+            // files interleave by log time, some logs share the same time
+            let millis = i * 20 + f % 4;
+            writeln!(
+                encoder,
+                "2021-09-27 01:{:02}:{:02}.{:03} [pod-{}] INFO request {} done\n  at stack",
+                millis / 60000,
+                millis / 1000 % 60,
+                millis % 1000,
+                f,
+                i
+            )?;
+        }
+        let file = dir.join(format!("pod-{}.log.gz", f));
+        fs::write(&file, encoder.finish()?)?;
+        files.push(file.to_str().unwrap().to_string());
+    }
+    let files = files.iter().map(|s| s.as_str()).collect::<Vec<&str>>();
+    let reduce = |output: &Path, threads: usize| {
+        let start = time::Instant::now();
+        reducer::reduce_logs(
+            &files,
+            &reducer::ReduceOptions {
+                threads: Some(threads),
+                ..reduce_options(output)
+            },
+        )?;
+        let elapsed = start.elapsed();
+        println!(
+            "reduce {} logs with {} threads in {:?}, {:.0} logs/s",
+            file_count * log_count,
+            threads,
+            elapsed,
+            (file_count * log_count) as f64 / elapsed.as_secs_f64()
+        );
+        Ok::<(), LogyError>(())
+    };
+
+    reduce(&dir.join("single.log"), 1)?;
+    reduce(&dir.join("pool.log"), 8)?;
+    let single = fs::read_to_string(dir.join("single.log"))?;
+    assert_eq!(single.lines().count() as i64, file_count * log_count * 2);
+    // merge order is the same regardless of pool size
+    assert!(single == fs::read_to_string(dir.join("pool.log"))?);
+    Ok(())
+}
+
+#[test]
+fn test_reduce_log_with_filters() -> TestResult {
+    let dir = prepare_dir("reduce-filters");
+    let file = write_file(
+        &dir.join("a.log"),
+        "2021-09-27 01:00:00.100 INFO order created\n\
+         2021-09-27 01:00:00.200 ERROR order failed\n  at PaymentClient\n\
+         2021-09-27 01:00:00.300 warn order slow\n\
+         2021-09-27 01:00:00.400 ERROR health check failed\n  at PaymentClient\n\
+         2021-09-27 01:00:00.500 no level PaymentClient\n\
+         2021-09-27 01:00:00.600 ERR disk full\n\
+         2021-09-27 01:00:00.700 CRITICAL out of memory\n\
+         2021-09-27 01:00:00.800 panic worker crashed\n",
+    );
+    let reduce = |output: &str, filter: FilterOptions| {
+        reducer::reduce_logs(
+            &[file.as_str()],
+            &reducer::ReduceOptions {
+                filter,
+                ..reduce_options(&dir.join(output))
+            },
+        )
+    };
+
+    // continuation lines are matched as part of log
+    reduce(
+        "grep.log",
+        FilterOptions {
+            grep: vec!["PaymentClient".to_string()],
+            grep_v: vec!["health".to_string()],
+            ..FilterOptions::default()
+        },
+    )?;
+    assert_eq!(
+        fs::read_to_string(dir.join("grep.log"))?,
+        "2021-09-27 01:00:00.200 ERROR order failed\n  at PaymentClient\n\
+         2021-09-27 01:00:00.500 no level PaymentClient\n"
+    );
+
+    reduce(
+        "level.log",
+        FilterOptions {
+            level: Some("WARN".to_string()),
+            ..FilterOptions::default()
+        },
+    )?;
+    assert_eq!(
+        fs::read_to_string(dir.join("level.log"))?,
+        "2021-09-27 01:00:00.200 ERROR order failed\n  at PaymentClient\n\
+         2021-09-27 01:00:00.300 warn order slow\n\
+         2021-09-27 01:00:00.400 ERROR health check failed\n  at PaymentClient\n\
+         2021-09-27 01:00:00.600 ERR disk full\n\
+         2021-09-27 01:00:00.700 CRITICAL out of memory\n\
+         2021-09-27 01:00:00.800 panic worker crashed\n"
+    );
+
+    let result = reduce(
+        "invalid.log",
+        FilterOptions {
+            level: Some("LOUD".to_string()),
+            ..FilterOptions::default()
+        },
+    );
+    assert!(matches!(result, Err(LogyError::Argument { .. })));
+    Ok(())
+}
+
+const JSON_LOGS: &str = r#"{"ts":1632704400500,"level":"INFO","ctx":{"traceId":"t1"},"msg":"millis"}
+{"ts":1632704401,"level":"error","ctx":{"traceId":"t2"},"msg":"seconds"}
+  at stack
+{ "cause": "continuation starts with brace"
+{"ts":"2021-09-27T01:00:06.200Z","level":"WARN","ctx":{"traceId":"t1"},"msg":"rfc3339"}
+{"ts":"2021-09-27T09:00:07+08:00","level":"INFO","ctx":{"traceId":"t2"},"msg":"offset"}
+"#;
+
+fn json_fields() -> JsonFields {
+    JsonFields {
+        time: Some("ts".to_string()),
+        trace: Some("ctx.traceId".to_string()),
+        level: Some("level".to_string()),
+    }
+}
+
+#[test]
+fn test_reduce_json_log() -> TestResult {
+    let dir = prepare_dir("reduce-json");
+    let json_file = write_file(&dir.join("a.json"), JSON_LOGS);
+    let text_file = write_file(
+        &dir.join("b.log"),
+        "2021-09-27 01:00:00.800 WARN [t1] text\n",
+    );
+    let reduce = |output: &str, filter: FilterOptions| {
+        reducer::reduce_logs(
+            &[json_file.as_str(), text_file.as_str()],
+            &reducer::ReduceOptions {
+                filter,
+                json: json_fields(),
+                ..reduce_options(&dir.join(output))
+            },
+        )
+    };
+
+    reduce("merged.log", FilterOptions::default())?;
+    let lines = JSON_LOGS.lines().collect::<Vec<&str>>();
+    assert_eq!(
+        fs::read_to_string(dir.join("merged.log"))?,
+        format!(
+            "{}\n2021-09-27 01:00:00.800 WARN [t1] text\n{}\n{}\n{}\n{}\n{}\n",
+            lines[0], lines[1], lines[2], lines[3], lines[4], lines[5]
+        )
+    );
+
+    // level of JSON logs is read from field
+    reduce(
+        "warn.log",
+        FilterOptions {
+            level: Some("WARN".to_string()),
+            ..FilterOptions::default()
+        },
+    )?;
+    assert_eq!(
+        fs::read_to_string(dir.join("warn.log"))?,
+        format!(
+            "2021-09-27 01:00:00.800 WARN [t1] text\n{}\n{}\n{}\n{}\n",
+            lines[1], lines[2], lines[3], lines[4]
+        )
+    );
+
+    // names are escaped in JSON pointer
+    assert_eq!(models::field_pointer("a~b.c/d"), "/a~0b/c~1d");
+    let parser =
+        models::LogTimeParser::new("^$", "%Y-%m-%d %H:%M:%S%.3f")?.with_json_fields(&JsonFields {
+            time: Some("@t/ms".to_string()),
+            ..JsonFields::default()
+        });
+    assert_eq!(
+        parser.parse(r#"{"@t/ms":1632704400500}"#),
+        Ok(1632704400500)
+    );
+
+    // epochs out of range are malformed
+    let parser =
+        models::LogTimeParser::new("^$", "%Y-%m-%d %H:%M:%S%.3f")?.with_json_fields(&json_fields());
+    for line in [r#"{"ts":9e18}"#, r#"{"ts":"inf"}"#, r#"{"ts":-1e300}"#] {
+        assert!(parser.parse(line).is_err(), "{} is accepted", line);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_trace_json_log() -> TestResult {
+    let dir = prepare_dir("trace-json");
+    let json_file = write_file(&dir.join("a.json"), JSON_LOGS);
+    let text_file = write_file(
+        &dir.join("b.log"),
+        "2021-09-27 01:00:00.800 WARN [t1] text\n",
+    );
+
+    let output = dir.join("traced.json");
+    let mut options = trace_options(&output, 300000, 1024);
+    options.merge = true;
+    options.report_format = tracer::ReportFormat::Json;
+    options.json = json_fields();
+    tracer::trace_log(&[json_file.as_str(), text_file.as_str()], &options)?;
+
+    let mut reports = fs::read_to_string(&output)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<serde_json::Value>, _>>()?;
+    reports.sort_by_key(|report| report["trace_id"].to_string());
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[0]["trace_id"], "t1");
+    assert_eq!(reports[0]["duration_ms"], 5700);
+    assert_eq!(reports[0]["line_count"], 3);
+    assert_eq!(reports[1]["trace_id"], "t2");
+    assert_eq!(reports[1]["duration_ms"], 6000);
+    assert_eq!(reports[1]["line_count"], 2);
+    Ok(())
+}
+
+#[test]
+fn test_log_format_presets() -> TestResult {
+    let year = chrono::Local::now().year();
+    // expected log times are in UTC
+    let samples = [
+        ("default", "2021-09-27 01:00:00.123 message", "2021-09-27 01:00:00.123"),
+        (
+            "logback",
+            "2021-09-27 01:00:00.123 [main] INFO  c.e.Application - started",
+            "2021-09-27 01:00:00.123",
+        ),
+        (
+            "log4j2",
+            "2021-09-27 01:00:00,123 [main] WARN  com.example.App - slow",
+            "2021-09-27 01:00:00.123",
+        ),
+        (
+            "spring-boot",
+            "2021-09-27 01:00:00.123  INFO 12345 --- [           main] c.e.Application          : started",
+            "2021-09-27 01:00:00.123",
+        ),
+        (
+            "nginx-access",
+            r#"127.0.0.1 - - [27/Sep/2021:01:00:00 +0800] "GET / HTTP/1.1" 200 612 "-" "curl/7.68.0""#,
+            "2021-09-26 17:00:00.000",
+        ),
+        (
+            "apache-combined",
+            r#"127.0.0.1 - frank [27/Sep/2021:01:00:00 -0700] "GET /a.gif HTTP/1.0" 200 2326 "-" "Mozilla/4.08""#,
+            "2021-09-27 08:00:00.000",
+        ),
+        (
+            "syslog-rfc3164",
+            "Sep  7 01:00:00 host sshd[123]: accepted",
+            &format!("{}-09-07 01:00:00.000", year),
+        ),
+        (
+            "syslog-rfc5424",
+            "<34>1 2021-09-27T01:00:00.003Z host app 123 ID47 - message",
+            "2021-09-27 01:00:00.003",
+        ),
+        (
+            "python-logging",
+            "2021-09-27 01:00:00,123 - app.module - ERROR - failed",
+            "2021-09-27 01:00:00.123",
+        ),
+        (
+            "go-zap",
+            "2021-09-27T01:00:00.123+0800\tINFO\tapp/main.go:12\tstarted",
+            "2021-09-26 17:00:00.123",
+        ),
+        (
+            "k8s-cri",
+            "2021-09-27T01:00:00.123456789Z stdout F message",
+            "2021-09-27 01:00:00.123",
+        ),
+    ];
+    assert_eq!(samples.len(), formats::FORMATS.len());
+
+    for (name, line, expected) in samples.iter() {
+        let format = name.parse::<LogFormat>()?;
+        let parser = models::LogTimeParser::new(format.pattern, format.time_format)?;
+        let expected = chrono::NaiveDateTime::parse_from_str(expected, "%Y-%m-%d %H:%M:%S%.3f")?;
+        assert_eq!(
+            parser.parse(line),
+            Ok(expected.timestamp_millis()),
+            "format {}",
+            name
+        );
+    }
+    assert!("unknown".parse::<LogFormat>().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_detect_log_format() -> TestResult {
+    let dir = prepare_dir("detect-format");
+    let logback = write_file(
+        &dir.join("logback.log"),
+        "2021-09-27 01:00:00.123 [main] INFO  c.e.Application - started\n\
+         2021-09-27 01:00:01.123 [main] ERROR c.e.Application - failed\n\
+         \tat c.e.Application.run(Application.java:12)\n",
+    );
+    let detections = formats::detect(&logback, 100, None)?;
+    assert_eq!(detections[0].format.name, "logback");
+    assert_eq!((detections[0].matched, detections[0].total), (2, 3));
+    // less specific formats match as well
+    assert!(detections.iter().any(|d| d.format.name == "default"));
+
+    let json = write_file(
+        &dir.join("app.json"),
+        "{\"@timestamp\":\"2021-09-27T01:00:00.123Z\",\"message\":\"started\"}\n",
+    );
+    let detections = formats::detect(&json, 100, None)?;
+    assert_eq!(detections[0].format.name, "json");
+    assert_eq!(detections[0].format.time_field, Some("@timestamp"));
+    assert_eq!(detections[0].rate(), 1.0);
+
+    // only sampled lines are checked
+    let plain = write_file(
+        &dir.join("plain.log"),
+        "no time here\n2021-09-27 01:00:00 late\n",
+    );
+    assert!(formats::detect(&plain, 1, None)?.is_empty());
+    assert_eq!(
+        formats::detect(&plain, 2, None)?[0].format.time_format,
+        "%Y-%m-%d %H:%M:%S"
+    );
+
+    // detected only when asked for, and not combined with log time format given
+    let format = |extra: &[&str]| {
+        let mut command = vec!["logy", "reduce"];
+        command.extend_from_slice(extra);
+        command.push(&logback);
+        let matches = super::command_args().get_matches_from(command);
+        let args = matches.subcommand_matches("reduce").unwrap();
+        super::log_format(args, &[&logback], None).map(|format| format.name)
+    };
+    assert_eq!(format(&[])?, "default");
+    assert_eq!(format(&["--format", "auto"])?, "logback");
+    assert_eq!(
+        format(&["--format", "auto", "--log-time", "%d/%b/%Y:%H:%M:%S %z"])?,
+        "default"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_trace_log_in_single_pass() -> TestResult {
+    let dir = prepare_dir("trace-single-pass");
+    let file = write_file(&dir.join("app.log"), TRACE_LOGS);
+    let expected = "========================= PT9S =========================\n\
+                    2021-09-27 01:00:00.000 [t1] start\n\
+                    2021-09-27 01:00:09.000 [t1] end\n  at stack\n\n\n\n\n\
+                    ========================= PT7S =========================\n\
+                    2021-09-27 01:00:03.000 [t3] start\n\
+                    2021-09-27 01:00:10.000 [t3] end\n\n\n\n\n";
+
+    let output = dir.join("traced.log");
+    tracer::trace_log(&[file.as_str()], &trace_options(&output, 300000, 1024))?;
+    assert_eq!(fs::read_to_string(&output)?, expected);
+
+    // spill all buffered logs to disk
+    let output = dir.join("spilled.log");
+    tracer::trace_log(&[file.as_str()], &trace_options(&output, 300000, 1))?;
+    assert_eq!(fs::read_to_string(&output)?, expected);
+
+    // traces are closed before reaching minimal duration
+    let output = dir.join("idle.log");
+    tracer::trace_log(&[file.as_str()], &trace_options(&output, 3000, 1024))?;
+    assert_eq!(fs::read_to_string(&output)?, "");
+
+    // only logs with trace ID are parsed, merged or not
+    let malformed = write_file(
+        &dir.join("malformed.log"),
+        &format!("{}2021-13-27 01:00:11.000 no trace\n", TRACE_LOGS),
+    );
+    let malformed_trace = write_file(
+        &dir.join("malformed-trace.log"),
+        &format!("{}2021-13-27 01:00:11.000 [t4] start\n", TRACE_LOGS),
+    );
+    for merge in [false, true] {
+        let output = dir.join(format!("malformed-{}.log", merge));
+        let mut options = trace_options(&output, 300000, 1024);
+        options.error_policy = ErrorPolicy::Abort;
+        options.merge = merge;
+        tracer::trace_log(&[malformed.as_str()], &options)?;
+        // merged logs are prefixed with source file
+        let traced = fs::read_to_string(&output)?.replace(&format!("{}:", malformed), "");
+        assert_eq!(traced, expected);
+        let result = tracer::trace_log(&[malformed_trace.as_str()], &options);
+        assert!(matches!(result, Err(LogyError::MalformedLog { .. })));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_trace_log_across_files() -> TestResult {
+    let dir = prepare_dir("trace-across-files");
+    let file_a = write_file(
+        &dir.join("a.log"),
+        "2021-09-27 01:59:58.000 [t1] request\n2021-09-27 01:59:59.000 [t2] request\n",
+    );
+    let file_b = write_file(
+        &dir.join("b.log"),
+        "2021-09-27 02:00:01.000 [t2] response\n2021-09-27 02:00:06.000 [t1] response\n",
+    );
+
+    let output = dir.join("traced.log");
+    let mut options = trace_options(&output, 300000, 1024);
+    options.merge = true;
+    tracer::trace_log(&[file_a.as_str(), file_b.as_str()], &options)?;
+
+    assert_eq!(
+        fs::read_to_string(&output)?,
+        format!(
+            "========================= PT8S =========================\n\
+             {a}:2021-09-27 01:59:58.000 [t1] request\n\
+             {b}:2021-09-27 02:00:06.000 [t1] response\n\n\n\n\n",
+            a = file_a,
+            b = file_b
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn test_trace_log_report() -> TestResult {
+    let dir = prepare_dir("trace-report");
+    let file = write_file(&dir.join("app.log"), TRACE_LOGS);
+
+    let output = dir.join("traced.json");
+    let mut options = trace_options(&output, 300000, 1024);
+    options.report_format = tracer::ReportFormat::Json;
+    tracer::trace_log(&[file.as_str()], &options)?;
+    let reports = fs::read_to_string(&output)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<serde_json::Value>, _>>()?;
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[0]["trace_id"], "t1");
+    assert_eq!(reports[0]["start_time"], "2021-09-27T01:00:00.000");
+    assert_eq!(reports[0]["end_time"], "2021-09-27T01:00:09.000");
+    assert_eq!(reports[0]["duration_ms"], 9000);
+    assert_eq!(reports[0]["line_count"], 2);
+    assert_eq!(reports[0]["files"][0], file.as_str());
+    assert_eq!(
+        reports[0]["first_line"],
+        "2021-09-27 01:00:00.000 [t1] start"
+    );
+    assert_eq!(reports[0]["last_line"], "2021-09-27 01:00:09.000 [t1] end");
+
+    // first and last lines are by log time, not by order received
+    let unordered = write_file(
+        &dir.join("unordered.log"),
+        "2021-09-27 01:00:05.000 [t1] middle\n\
+         2021-09-27 01:00:00.000 [t1] start\n\
+         2021-09-27 01:00:09.000 [t1] end\n\
+         2021-09-27 01:00:07.000 [t1] late\n",
+    );
+    let output = dir.join("unordered.json");
+    let mut options = trace_options(&output, 300000, 1024);
+    options.report_format = tracer::ReportFormat::Json;
+    tracer::trace_log(&[unordered.as_str()], &options)?;
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&output)?)?;
+    assert_eq!(report["first_line"], "2021-09-27 01:00:00.000 [t1] start");
+    assert_eq!(report["last_line"], "2021-09-27 01:00:09.000 [t1] end");
+
+    let output = dir.join("traced.csv");
+    let mut options = trace_options(&output, 300000, 1024);
+    options.report_format = tracer::ReportFormat::Csv;
+    tracer::trace_log(&[file.as_str()], &options)?;
+    assert_eq!(
+        fs::read_to_string(&output)?,
+        format!(
+            "trace_id,start_time,end_time,duration_ms,line_count,files,first_line,last_line,unfinished\n\
+             t1,2021-09-27T01:00:00.000,2021-09-27T01:00:09.000,9000,2,{f},\
+             2021-09-27 01:00:00.000 [t1] start,2021-09-27 01:00:09.000 [t1] end,false\n\
+             t3,2021-09-27T01:00:03.000,2021-09-27T01:00:10.000,7000,2,{f},\
+             2021-09-27 01:00:03.000 [t3] start,2021-09-27 01:00:10.000 [t3] end,false\n",
+            f = file
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn test_latency_stats() {
+    let mut stats = LatencyStats::new(true);
+    let hour = 3600 * 1000;
+    for duration in 1..=100 {
+        stats.add(hour * (duration % 2), duration);
+    }
+
+    let output = stats.render();
+    let lines = output.lines().collect::<Vec<&str>>();
+    assert_eq!(
+        &lines[..9],
+        &[
+            "count: 100",
+            "min: 1 ms",
+            "max: 100 ms",
+            "mean: 50.50 ms",
+            "p50: 50 ms",
+            "p90: 90 ms",
+            "p95: 95 ms",
+            "p99: 99 ms",
+            "p99.9: 100 ms",
+        ]
+    );
+    assert_eq!(
+        lines[9],
+        format!("{:>12} |{:<40}| 9", "< 10 ms", "#".repeat(8))
+    );
+    assert_eq!(lines[13], format!("{:>12} |{:<40}| 1", "< 200 ms", "#"));
+    assert!(output.contains("\n==== 1970-01-01 00:00 ====\ncount: 50\nmin: 2 ms\n"));
+    assert!(output.contains("\n==== 1970-01-01 01:00 ====\ncount: 50\nmin: 1 ms\n"));
+
+    // durations by hour are not kept unless wanted
+    let mut stats = LatencyStats::new(false);
+    stats.add(hour, 1);
+    assert!(!stats.render().contains("===="));
+}
+
+#[test]
+fn test_trace_log_with_marks() -> TestResult {
+    let dir = prepare_dir("trace-with-marks");
+    let file = write_file(
+        &dir.join("app.log"),
+        "2021-09-27 01:00:00.000 [t1] Request received\n\
+         2021-09-27 01:00:01.000 [t2] Request received\n\
+         2021-09-27 01:00:02.000 [t1] Response sent\n\
+         2021-09-27 01:00:03.000 [t3] Request received\n\
+         2021-09-27 01:00:09.000 [t1] background job done\n\
+         2021-09-27 01:00:10.000 [t3] Response sent\n",
+    );
+
+    let output = dir.join("traced.csv");
+    let mut options = trace_options(&output, 300000, 1024);
+    options.report_format = tracer::ReportFormat::Csv;
+    options.start_pattern = Some("Request received".to_string());
+    options.end_pattern = Some("Response sent".to_string());
+    tracer::trace_log(&[file.as_str()], &options)?;
+
+    // t1 costs 2 seconds between marks, t2 never ends
+    assert_eq!(
+        fs::read_to_string(&output)?,
+        format!(
+            "trace_id,start_time,end_time,duration_ms,line_count,files,first_line,last_line,unfinished\n\
+             t2,2021-09-27T01:00:01.000,2021-09-27T01:00:01.000,0,1,{f},\
+             2021-09-27 01:00:01.000 [t2] Request received,2021-09-27 01:00:01.000 [t2] Request received,true\n\
+             t3,2021-09-27T01:00:03.000,2021-09-27T01:00:10.000,7000,2,{f},\
+             2021-09-27 01:00:03.000 [t3] Request received,2021-09-27 01:00:10.000 [t3] Response sent,false\n",
+            f = file
+        )
+    );
+
+    // without start marks, traces missing end mark are measured by their logs and not unfinished
+    let output = dir.join("end-only.csv");
+    options.output_file_pattern = output.to_str().unwrap().to_string();
+    options.start_pattern = None;
+    tracer::trace_log(&[file.as_str()], &options)?;
+    assert_eq!(
+        fs::read_to_string(&output)?,
+        format!(
+            "trace_id,start_time,end_time,duration_ms,line_count,files,first_line,last_line,unfinished\n\
+             t3,2021-09-27T01:00:03.000,2021-09-27T01:00:10.000,7000,2,{f},\
+             2021-09-27 01:00:03.000 [t3] Request received,2021-09-27 01:00:10.000 [t3] Response sent,false\n",
+            f = file
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn test_follow_reader() -> TestResult {
+    let dir = prepare_dir("follow-reader");
+    let path = dir.join("app.log");
+    fs::write(&path, "2021-09-27 01:00:00.000 old\n")?;
+    let prefix = r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#;
+    let timeout = time::Duration::from_secs(60);
+    let mut reader = FollowReader::new(path.to_str().unwrap(), prefix, false)?;
+    assert!(reader.poll(timeout)?.is_empty());
+
+    // partial line waits for line break, last log waits for next log
+    let mut file = OpenOptions::new().append(true).open(&path)?;
+    write!(
+        file,
+        "2021-09-27 01:00:01.000 first\n  at stack\n2021-09-27 01:00"
+    )?;
+    assert!(reader.poll(timeout)?.is_empty());
+    writeln!(file, ":02.000 second")?;
+    assert_eq!(
+        reader.poll(timeout)?,
+        vec![(1, "2021-09-27 01:00:01.000 first\n  at stack".to_string())]
+    );
+    assert_eq!(
+        reader.poll(time::Duration::from_secs(0))?,
+        vec![(3, "2021-09-27 01:00:02.000 second".to_string())]
+    );
+
+    // truncated
+    fs::write(&path, "2021-09-27 01:00:03.000 truncated\n")?;
+    assert_eq!(
+        reader.poll(time::Duration::from_secs(0))?,
+        vec![(1, "2021-09-27 01:00:03.000 truncated".to_string())]
+    );
+
+    // renamed and recreated
+    writeln!(file, "2021-09-27 01:00:04.000 before rotate")?;
+    fs::rename(&path, dir.join("app.log.1"))?;
+    fs::write(&path, "2021-09-27 01:00:05.000 rotated\n")?;
+    assert_eq!(
+        reader.poll(time::Duration::from_secs(0))?,
+        vec![
+            (2, "2021-09-27 01:00:04.000 before rotate".to_string()),
+            (1, "2021-09-27 01:00:05.000 rotated".to_string())
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_tail_logs() -> TestResult {
+    let dir = prepare_dir("tail-logs");
+    let file_a = write_file(
+        &dir.join("a.log"),
+        "2021-09-27 01:00:00.100 a1\n2021-09-27 01:00:00.300 a2\n  at stack\n",
+    );
+    let file_b = write_file(&dir.join("b.log"), "2021-09-27 01:00:00.200 b1\n");
+    let output = dir.join("output.log");
+
+    follower::tail_logs(
+        &[file_a.as_str(), file_b.as_str()],
+        &follower::TailOptions {
+            pattern: r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#.to_string(),
+            log_time_format: "%Y-%m-%d %H:%M:%S%.3f".to_string(),
+            output_file_pattern: output.to_str().unwrap().to_string(),
+            follow: false,
+            from_beginning: true,
+            reorder_window: 1000,
+            release_timeout: 1000,
+            flush_timeout: 500,
+            error_policy: ErrorPolicy::Warn,
+            bucket: None,
+            time_zones: TimeZones::default(),
+        },
+        &AtomicBool::new(true),
+    )?;
+
+    assert_eq!(
+        fs::read_to_string(&output)?,
+        "2021-09-27 01:00:00.100 a1\n\
+         2021-09-27 01:00:00.200 b1\n\
+         2021-09-27 01:00:00.300 a2\n  at stack\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_tail_logs_until_stopped() -> TestResult {
+    let dir = prepare_dir("tail-logs-stopped");
+    let file = write_file(
+        &dir.join("a.log"),
+        "2021-09-27 01:00:00.100 a1\n2021-09-27 01:00:00.300 a2\n",
+    );
+    let output = dir.join("output.log");
+    let running = AtomicBool::new(true);
+
+    // logs are held by window and timeout, until following is stopped
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            thread::sleep(time::Duration::from_millis(500));
+            running.store(false, Ordering::Relaxed);
+        });
+        follower::tail_logs(
+            &[file.as_str()],
+            &follower::TailOptions {
+                pattern: r#"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})"#.to_string(),
+                log_time_format: "%Y-%m-%d %H:%M:%S%.3f".to_string(),
+                output_file_pattern: output.to_str().unwrap().to_string(),
+                follow: true,
+                from_beginning: true,
+                reorder_window: 60000,
+                release_timeout: 60000,
+                flush_timeout: 60000,
+                error_policy: ErrorPolicy::Warn,
+                bucket: None,
+                time_zones: TimeZones::default(),
+            },
+            &running,
+        )
+    })?;
+
+    assert_eq!(
+        fs::read_to_string(&output)?,
+        "2021-09-27 01:00:00.100 a1\n2021-09-27 01:00:00.300 a2\n"
+    );
+    Ok(())
+}
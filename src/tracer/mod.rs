@@ -1,167 +1,703 @@
-use chrono::{Duration, NaiveDateTime};
-use log::info;
+use chrono::{Duration, TimeZone};
+use chrono_tz::Tz;
+use log::{debug, info};
 use regex::Regex;
-use std::{cmp, collections::HashMap, io::Result, vec};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    cmp,
+    collections::{BTreeSet, HashMap},
+    env, fs,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    iter,
+    path::PathBuf,
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
-use super::models::{Log, LogDuration, NextLogLineFinder, WrappedFileReader, WrappedFileWriter};
+use super::errors::{ErrorHandler, ErrorPolicy, LogyError, Result};
+use super::models::{
+    field_pointer, instant_to_local, json_text, parse_json, Codec, JsonFields, Log, LogDuration,
+    LogTimeParser, NextLogLineFinder, TimeRange, TimeZones, WrappedFileReader, WrappedFileWriter,
+    WriterOptions, STDIO,
+};
+use super::reducer;
+use super::stats::LatencyStats;
+use super::tagger::SourceTagger;
 
-pub fn trace_log(
-    files: &Vec<&str>,
-    min_cost_time: i64,
-    pattern: &str,
-    log_time_format: &str,
-    trace_pattern: &str,
-    output_file_pattern: &str,
-) -> Result<()> {
-    let re = Regex::new(trace_pattern).unwrap();
-    let parse_log_time_pattern = Regex::new(&pattern.to_string()).unwrap();
+// trace collectors in this process, to name temp directory of each collector
+static COLLECTOR_COUNT: AtomicU64 = AtomicU64::new(0);
 
-    for &file in files {
-        info!("load file {} to collect cost time", file);
-        let mut log_groups: HashMap<String, LogDuration> = HashMap::new();
-        let mut reader = WrappedFileReader::new(file, pattern, true);
-        while let Log::Line(line) = reader.next_log() {
-            if let Some(captures) = re.captures(line.as_str()) {
-                let trace_id = captures.get(1).unwrap().as_str().to_string();
-                let log_time_string = parse_log_time_pattern
-                    .captures(&line)
-                    .unwrap()
-                    .get(1)
-                    .unwrap()
-                    .as_str()
-                    .to_string();
-                let log_time =
-                    NaiveDateTime::parse_from_str(&log_time_string, log_time_format).unwrap();
-                let log_time_millis = log_time.timestamp_millis();
-
-                if let Some(item) = log_groups.get(&trace_id) {
-                    let newone = LogDuration {
-                        trace_id: item.trace_id.to_string(),
-                        start_time: cmp::min(item.start_time, log_time_millis),
-                        end_time: cmp::max(item.end_time, log_time_millis),
-                    };
-                    &log_groups.insert(trace_id, newone);
-                } else {
-                    &log_groups.insert(
-                        trace_id.clone(),
-                        LogDuration {
-                            trace_id: trace_id.clone(),
-                            start_time: log_time_millis,
-                            end_time: log_time_millis,
-                        },
-                    );
-                }
-            }
+pub struct TraceOptions {
+    // prefix pattern to determine start of log
+    pub pattern: String,
+    pub log_time_format: String,
+    // first capture group is the trace ID
+    pub trace_pattern: String,
+    pub output_file_pattern: String,
+    // minimal duration of traced process in milliseconds
+    pub min_cost_time: i64,
+    // trace is closed when no log comes within this milliseconds
+    pub idle_timeout: i64,
+    // bytes of buffered logs kept in memory, exceeded logs are spilled to disk
+    pub memory_limit: usize,
+    pub error_policy: ErrorPolicy,
+    // merge all files into one timeline before tracing
+    pub merge: bool,
+    pub report_format: ReportFormat,
+    // print latency statistics of all traces
+    pub stats: bool,
+    // print latency statistics for each hour as well
+    pub stats_hourly: bool,
+    // log marks start of trace, duration is measured from the first matched log
+    pub start_pattern: Option<String>,
+    // log marks end of trace, duration is measured to the first matched log
+    pub end_pattern: Option<String>,
+    // milliseconds of time period per output file
+    pub bucket: Option<i64>,
+    // level of output compression, 0 for plain text
+    pub compress_level: u32,
+    pub codec: Codec,
+    // zstd dictionary of compressed inputs, and to compress small trace files better
+    pub zstd_dictionary: Option<Arc<Vec<u8>>>,
+    // logs out of time range are skipped
+    pub time_range: TimeRange,
+    // trace ID is read from trace field of JSON logs
+    pub json: JsonFields,
+    // time zones of log times without offset, times in reports are of default one
+    pub time_zones: TimeZones,
+    // template to tag logs with source files, like `[{basename}] `
+    pub tag_source: Option<String>,
+    // named captures of file path used as placeholders of tag template
+    pub source_pattern: Option<String>,
+}
+
+/// Output format of long traces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// banner with duration followed by logs of trace
+    Text,
+    /// one JSON object of trace summary per line
+    Json,
+    /// trace summary in comma separated values with header
+    Csv,
+}
+
+impl FromStr for ReportFormat {
+    type Err = LogyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            "csv" => Ok(ReportFormat::Csv),
+            _ => Err(LogyError::argument("report", s)),
         }
+    }
+}
+
+/// Summary of a long trace for machine-readable report
+#[derive(Debug, Serialize)]
+struct TraceReport<'a> {
+    trace_id: &'a str,
+    start_time: String,
+    end_time: String,
+    duration_ms: i64,
+    line_count: u64,
+    files: Vec<&'a str>,
+    // tags of source files when logs are tagged
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sources: Vec<String>,
+    first_line: &'a str,
+    last_line: &'a str,
+    // trace has start mark but no end mark
+    unfinished: bool,
+}
 
-        info!("{} entries collected", log_groups.len());
-        let mut long_duration_logs = log_groups
-            .values()
-            .filter(|&d| d.end_time - d.start_time > min_cost_time)
-            .map(|d| {
-                (
-                    d.trace_id.to_string(),
-                    LogDuration {
-                        trace_id: d.trace_id.to_string(),
-                        start_time: d.start_time,
-                        end_time: d.end_time,
-                    },
-                )
-            })
-            .collect::<HashMap<String, LogDuration>>();
+const CSV_HEADER: &str =
+    "trace_id,start_time,end_time,duration_ms,line_count,files,first_line,last_line,unfinished";
 
-        log_groups.clear();
+impl<'a> TraceReport<'a> {
+    fn to_csv(&self) -> String {
+        [
+            csv_field(self.trace_id),
+            csv_field(&self.start_time),
+            csv_field(&self.end_time),
+            self.duration_ms.to_string(),
+            self.line_count.to_string(),
+            csv_field(&self.files.join(";")),
+            csv_field(self.first_line),
+            csv_field(self.last_line),
+            self.unfinished.to_string(),
+        ]
+        .join(",")
+            + &if self.sources.is_empty() {
+                String::new()
+            } else {
+                format!(",{}", csv_field(&self.sources.join(";")))
+            }
+    }
+}
 
+/// quote field when it contains delimiter, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// local time of epoch milliseconds in time zone
+fn format_time(millis: i64, zone: &Tz) -> String {
+    zone.timestamp_millis(millis)
+        .format("%Y-%m-%dT%H:%M:%S%.3f")
+        .to_string()
+}
+
+pub fn trace_log(files: &[&str], options: &TraceOptions) -> Result<()> {
+    let trace_id = Arc::new(TraceIdCapture::new(options)?);
+    let parser = LogTimeParser::new(&options.pattern, &options.log_time_format)?
+        .with_json_fields(&options.json)
+        .with_time_zones(&options.time_zones);
+    let mut error_handler = ErrorHandler::new(options.error_policy);
+    let mut stats = LatencyStats::new(options.stats_hourly);
+
+    if options.merge {
         info!(
-            "{} entries cost time over than {} ms",
-            long_duration_logs.len(),
-            min_cost_time
+            "start to trace long process logs from {} files",
+            files.len()
         );
+        let mut collector = TraceCollector::new(options, &parser)?;
 
-        let mut grouped_logs: HashMap<String, Vec<String>> = HashMap::new();
-        reader = WrappedFileReader::new(file, pattern, true);
-        let mut writer = WrappedFileWriter::new(output_file_pattern, 0);
-
-        info!("start to output long process logs from {}", file);
-        while let Log::Line(line) = reader.next_log() {
-            if let Some(captures) = re.captures(line.as_str()) {
-                let trace_id = captures.get(1).unwrap().as_str().to_string();
-                let log_time_string = parse_log_time_pattern
-                    .captures(&line)
-                    .unwrap()
-                    .get(1)
-                    .unwrap()
-                    .as_str()
-                    .to_string();
-                let log_time =
-                    NaiveDateTime::parse_from_str(&log_time_string, log_time_format).unwrap();
-                let log_time_millis = log_time.timestamp_millis();
-
-                if let Some(_) = long_duration_logs.get(&trace_id) {
-                    if let Some(value) = grouped_logs.get_mut(&trace_id) {
-                        value.push(line);
-                    } else {
-                        grouped_logs.insert(trace_id.clone(), vec![line]);
+        // like tracing each file, log time is parsed only for logs with trace ID
+        let capture = Arc::clone(&trace_id);
+        let logs = reducer::merge_files(
+            files,
+            &options.pattern,
+            parser,
+            options.time_range,
+            None,
+            options.zstd_dictionary.clone(),
+            Some(Arc::new(move |line, json| {
+                capture.capture(line, json).is_some()
+            })),
+        );
+        for log in logs.iter().flatten() {
+            match log {
+                Ok(log) => {
+                    let line = log.value();
+                    if let Some(trace_id) = trace_id.capture(&line, None) {
+                        collector.add(trace_id, log.time(), &log.filename(), line)?;
                     }
                 }
-
-                // find ended log and output
-                let reached_ended_logs = long_duration_logs
-                    .values()
-                    .filter(|&duration| duration.end_time < log_time_millis)
-                    .map(|duration| duration.trace_id.clone())
-                    .collect::<Vec<String>>();
-
-                write_long_logs(
-                    &mut writer,
-                    &mut long_duration_logs,
-                    &mut grouped_logs,
-                    &reached_ended_logs,
-                );
+                Err(e @ LogyError::MalformedLog { .. }) => error_handler.handle(e)?,
+                Err(e) => return Err(e),
             }
         }
+        logs.join()?;
 
-        // output remained
-        let trace_ids = long_duration_logs
-            .keys()
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
+        if let Some(collected) = collector.finish()? {
+            stats.extend(collected);
+        }
+    } else {
+        trace_files(
+            files,
+            options,
+            &trace_id,
+            &parser,
+            &mut error_handler,
+            &mut stats,
+        )?;
+    }
+    error_handler.report();
 
-        write_long_logs(
-            &mut writer,
-            &mut long_duration_logs,
-            &mut grouped_logs,
-            &trace_ids,
-        );
+    if options.stats || options.stats_hourly {
+        let output = stats.render();
+        if options.output_file_pattern == STDIO {
+            // keep traced logs in stdout clean
+            eprint!("{}", output);
+        } else {
+            print!("{}", output);
+        }
+    }
+
+    Ok(())
+}
 
+/// trace each file separately
+fn trace_files(
+    files: &[&str],
+    options: &TraceOptions,
+    trace_id: &TraceIdCapture,
+    parser: &LogTimeParser,
+    error_handler: &mut ErrorHandler,
+    stats: &mut LatencyStats,
+) -> Result<()> {
+    for &file in files {
+        info!("start to trace long process logs from {}", file);
+        let mut reader = WrappedFileReader::new(
+            file,
+            &options.pattern,
+            options.zstd_dictionary.as_deref().map(Vec::as_slice),
+        )?
+        .with_json_lines(parser.is_json());
+        let mut collector = TraceCollector::new(options, parser)?;
+
+        while let Log::Line(line) = reader.next_log()? {
+            if let Some(trace_id) = trace_id.capture(&line, reader.json()) {
+                match parser.parse_json_log(file, &line, reader.json()) {
+                    Ok(log_time_millis) if options.time_range.is_passed(log_time_millis) => {
+                        debug!("time range passed, stop reading {}", file);
+                        break;
+                    }
+                    Ok(log_time_millis) if !options.time_range.contains(log_time_millis) => {}
+                    Ok(log_time_millis) => collector.add(trace_id, log_time_millis, file, line)?,
+                    Err(cause) => error_handler.handle(LogyError::malformed(
+                        file,
+                        reader.line_number(),
+                        &cause,
+                    ))?,
+                }
+            }
+        }
+
+        if let Some(collected) = collector.finish()? {
+            stats.extend(collected);
+        }
         info!("finish output long process logs from {}", file);
     }
 
     Ok(())
 }
 
-fn write_long_logs(
-    writer: &mut WrappedFileWriter,
-    long_duration_logs: &mut HashMap<String, LogDuration>,
-    grouped_logs: &mut HashMap<String, Vec<String>>,
-    trace_ids: &Vec<String>,
-) {
-    for trace_id in trace_ids {
-        let lines = grouped_logs.get_mut(trace_id).unwrap();
-        let duration = long_duration_logs.get(trace_id).unwrap();
-        lines.insert(
-            0,
-            format!(
-                "========================= {} =========================",
-                Duration::milliseconds(duration.end_time - duration.start_time)
-            ),
+fn compile_mark(pattern: &Option<String>) -> Result<Option<Regex>> {
+    match pattern {
+        Some(pattern) => Ok(Some(
+            Regex::new(pattern).map_err(|e| LogyError::pattern(pattern, e))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+fn is_marked(mark: &Option<Regex>, line: &str) -> bool {
+    mark.as_ref().is_some_and(|re| re.is_match(line))
+}
+
+/// Captures trace ID by pattern, or by field of JSON logs
+struct TraceIdCapture {
+    // first capture group is the trace ID
+    pattern: Regex,
+    // JSON pointer of trace ID in JSON logs
+    field: Option<String>,
+}
+
+impl TraceIdCapture {
+    fn new(options: &TraceOptions) -> Result<TraceIdCapture> {
+        Ok(TraceIdCapture {
+            pattern: Regex::new(&options.trace_pattern)
+                .map_err(|e| LogyError::pattern(&options.trace_pattern, e))?,
+            field: options.json.trace.as_deref().map(field_pointer),
+        })
+    }
+
+    /// capture trace ID of log, JSON logs are parsed unless `json` is parsed already
+    fn capture(&self, line: &str, json: Option<&Value>) -> Option<String> {
+        if let Some(field) = &self.field {
+            let parsed = json.is_none().then(|| parse_json(line)).flatten();
+            if let Some(object) = json.or(parsed.as_ref()) {
+                return object.pointer(field).and_then(json_text);
+            }
+        }
+        self.pattern
+            .captures(line)
+            .and_then(|captures| captures.get(1))
+            .map(|m| m.as_str().to_string())
+    }
+}
+
+/// Logs of a trace which is not closed yet
+struct TraceBuffer {
+    duration: LogDuration,
+    // logs kept in memory
+    lines: Vec<String>,
+    // bytes of logs kept in memory
+    size: usize,
+    // file of logs spilled to disk, logs in file are earlier than logs in memory
+    spill_file: Option<PathBuf>,
+    line_count: u64,
+    // source files of logs
+    files: BTreeSet<String>,
+    // first line of earliest and latest log by log time
+    first_line: String,
+    last_line: String,
+    // log time of start and end marks
+    mark_start: Option<i64>,
+    mark_end: Option<i64>,
+}
+
+impl TraceBuffer {
+    /// duration between marks, or between first and last log when mark not found
+    fn span(&self) -> LogDuration {
+        LogDuration {
+            trace_id: self.duration.trace_id.clone(),
+            start_time: self.mark_start.unwrap_or(self.duration.start_time),
+            end_time: self.mark_end.unwrap_or(self.duration.end_time),
+        }
+    }
+}
+
+/// Groups logs by trace ID in single pass, a trace is written once it becomes idle
+struct TraceCollector<'a> {
+    options: &'a TraceOptions,
+    writer: WrappedFileWriter,
+    traces: HashMap<String, TraceBuffer>,
+    // open traces ordered by last log time
+    activities: BTreeSet<(i64, String)>,
+    // bytes of all logs kept in memory
+    memory: usize,
+    spill_dir: PathBuf,
+    spill_count: u64,
+    trace_count: u64,
+    long_trace_count: u64,
+    // durations of closed traces, only collected when stats are wanted
+    stats: Option<LatencyStats>,
+    start_mark: Option<Regex>,
+    end_mark: Option<Regex>,
+    // tags logs with source files, log time is located by parser
+    tagger: Option<(SourceTagger, LogTimeParser)>,
+}
+
+impl<'a> TraceCollector<'a> {
+    fn new(options: &'a TraceOptions, parser: &LogTimeParser) -> Result<TraceCollector<'a>> {
+        let mut writer = WrappedFileWriter::new(
+            &options.output_file_pattern,
+            WriterOptions {
+                bucket: options.bucket,
+                time_zone: options.time_zones.default,
+                codec: options.codec,
+                zstd_dictionary: options.zstd_dictionary.clone(),
+                ..WriterOptions::new(options.compress_level)
+            },
+        )?;
+        let tagger = match &options.tag_source {
+            Some(template) => Some((
+                SourceTagger::new(template, options.source_pattern.as_deref())?,
+                parser.clone(),
+            )),
+            None => None,
+        };
+        if options.report_format == ReportFormat::Csv {
+            if tagger.is_some() {
+                writer = writer.with_header(&format!("{},sources", CSV_HEADER));
+            } else {
+                writer = writer.with_header(CSV_HEADER);
+            }
+        }
+
+        Ok(TraceCollector {
+            options,
+            writer,
+            traces: HashMap::new(),
+            activities: BTreeSet::new(),
+            memory: 0,
+            spill_dir: env::temp_dir().join(format!(
+                "logy-trace-{}-{}",
+                process::id(),
+                COLLECTOR_COUNT.fetch_add(1, Ordering::Relaxed)
+            )),
+            spill_count: 0,
+            trace_count: 0,
+            long_trace_count: 0,
+            stats: (options.stats || options.stats_hourly)
+                .then(|| LatencyStats::new(options.stats_hourly)),
+            start_mark: compile_mark(&options.start_pattern)?,
+            end_mark: compile_mark(&options.end_pattern)?,
+            tagger,
+        })
+    }
+
+    fn add(
+        &mut self,
+        trace_id: String,
+        log_time_millis: i64,
+        file: &str,
+        line: String,
+    ) -> Result<()> {
+        self.close_idle_traces(log_time_millis)?;
+
+        let line = match self.tagger.as_mut() {
+            Some((tagger, parser)) => tagger.apply(&line, file, parser.time_end(&line)),
+            None => line,
+        };
+        let head_line = line.lines().next().unwrap_or_default().to_string();
+        let trace = self
+            .traces
+            .entry(trace_id.clone())
+            .or_insert_with(|| TraceBuffer {
+                duration: LogDuration {
+                    trace_id: trace_id.clone(),
+                    start_time: log_time_millis,
+                    end_time: log_time_millis,
+                },
+                lines: Vec::new(),
+                size: 0,
+                spill_file: None,
+                line_count: 0,
+                files: BTreeSet::new(),
+                first_line: head_line.clone(),
+                last_line: String::new(),
+                mark_start: None,
+                mark_end: None,
+            });
+
+        self.activities
+            .remove(&(trace.duration.end_time, trace_id.clone()));
+        // logs of same time keep the first one received as earliest and last one as latest
+        if log_time_millis < trace.duration.start_time {
+            trace.first_line = head_line.clone();
+        }
+        if log_time_millis >= trace.duration.end_time {
+            trace.last_line = head_line;
+        }
+        trace.duration.start_time = cmp::min(trace.duration.start_time, log_time_millis);
+        trace.duration.end_time = cmp::max(trace.duration.end_time, log_time_millis);
+        trace.line_count += 1;
+        if !trace.files.contains(file) {
+            trace.files.insert(file.to_string());
+        }
+        self.activities.insert((trace.duration.end_time, trace_id));
+
+        if trace.mark_start.is_none() && is_marked(&self.start_mark, &line) {
+            trace.mark_start = Some(log_time_millis);
+            // end mark before start belongs to previous process
+            if trace.mark_end.is_some_and(|end| end < log_time_millis) {
+                trace.mark_end = None;
+            }
+        }
+        if trace.mark_end.is_none() && is_marked(&self.end_mark, &line) {
+            trace.mark_end = Some(log_time_millis);
+        }
+
+        // logs are only output in text report
+        if self.options.report_format == ReportFormat::Text {
+            let line = if self.options.merge && self.tagger.is_none() {
+                // tag line with source file unless tagged by template already
+                format!("{}:{}", file, line)
+            } else {
+                line
+            };
+            self.memory += line.len();
+            trace.size += line.len();
+            trace.lines.push(line);
+        }
+
+        if self.memory > self.options.memory_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// close traces which have no log since idle timeout
+    fn close_idle_traces(&mut self, log_time_millis: i64) -> Result<()> {
+        while let Some((end_time, trace_id)) = self.activities.iter().next().cloned() {
+            if log_time_millis - end_time <= self.options.idle_timeout {
+                break;
+            }
+            self.activities.remove(&(end_time, trace_id.clone()));
+            self.close(&trace_id)?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, trace_id: &str) -> Result<()> {
+        if let Some(trace) = self.traces.remove(trace_id) {
+            self.memory -= trace.size;
+
+            let span = trace.span();
+            let cost_time = span.end_time - span.start_time;
+            // trace which has a start mark but no end mark is always reported, and left out of
+            // stats as its duration is partial
+            let unfinished =
+                self.end_mark.is_some() && trace.mark_start.is_some() && trace.mark_end.is_none();
+            self.trace_count += 1;
+            if let Some(stats) = self.stats.as_mut().filter(|_| !unfinished) {
+                let zone = self.options.time_zones.default_zone();
+                // hours of stats are of local time
+                stats.add(instant_to_local(span.start_time, &zone), cost_time);
+            }
+            if cost_time > self.options.min_cost_time || unfinished {
+                self.long_trace_count += 1;
+                debug!(
+                    "trace {} cost {} ms{}",
+                    span.trace_id,
+                    cost_time,
+                    if unfinished { ", unfinished" } else { "" }
+                );
+                self.write_trace(&trace, &span, unfinished)?;
+            }
+
+            if let Some(spill_file) = &trace.spill_file {
+                fs::remove_file(spill_file)
+                    .map_err(|e| LogyError::io(&spill_file.to_string_lossy(), e))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_trace(
+        &mut self,
+        trace: &TraceBuffer,
+        duration: &LogDuration,
+        unfinished: bool,
+    ) -> Result<()> {
+        let zone = self.options.time_zones.default_zone();
+        let report = TraceReport {
+            trace_id: &duration.trace_id,
+            start_time: format_time(duration.start_time, &zone),
+            end_time: format_time(duration.end_time, &zone),
+            duration_ms: duration.end_time - duration.start_time,
+            line_count: trace.line_count,
+            files: trace.files.iter().map(|file| file.as_str()).collect(),
+            sources: match self.tagger.as_mut() {
+                Some((tagger, _)) => {
+                    let mut sources = trace
+                        .files
+                        .iter()
+                        .map(|file| tagger.tag(file).trim().to_string())
+                        .collect::<Vec<String>>();
+                    sources.sort();
+                    sources.dedup();
+                    sources
+                }
+                None => Vec::new(),
+            },
+            first_line: &trace.first_line,
+            last_line: &trace.last_line,
+            unfinished,
+        };
+
+        match self.options.report_format {
+            ReportFormat::Text => write_long_logs(&mut self.writer, duration, unfinished, trace),
+            ReportFormat::Json => {
+                let json = serde_json::to_string(&report)
+                    .map_err(|e| LogyError::io(&self.options.output_file_pattern, e.into()))?;
+                self.writer.write(duration.end_time, &json)
+            }
+            ReportFormat::Csv => self.writer.write(duration.end_time, &report.to_csv()),
+        }
+    }
+
+    /// move logs of least recently active traces to disk, until memory usage is down to 3/4 of limit
+    fn spill(&mut self) -> Result<()> {
+        let target = self.options.memory_limit / 4 * 3;
+        let mut released = 0;
+        let mut trace_ids = Vec::new();
+        for (_, trace_id) in &self.activities {
+            if self.memory - released <= target {
+                break;
+            }
+            if let Some(trace) = self.traces.get(trace_id) {
+                if !trace.lines.is_empty() {
+                    released += trace.size;
+                    trace_ids.push(trace_id.clone());
+                }
+            }
+        }
+
+        for trace_id in trace_ids {
+            let trace = match self.traces.get_mut(&trace_id) {
+                Some(trace) => trace,
+                None => continue,
+            };
+
+            if trace.spill_file.is_none() {
+                let spill_dir = &self.spill_dir;
+                fs::create_dir_all(spill_dir)
+                    .map_err(|e| LogyError::io(&spill_dir.to_string_lossy(), e))?;
+                self.spill_count += 1;
+                trace.spill_file = Some(self.spill_dir.join(format!("{}.log", self.spill_count)));
+            }
+            let spill_file = trace.spill_file.as_ref().unwrap();
+            let spill_filename = spill_file.to_string_lossy().to_string();
+            debug!("spill trace {} to {}", trace_id, spill_filename);
+
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(spill_file)
+                .map_err(|e| LogyError::io(&spill_filename, e))?;
+            for line in &trace.lines {
+                writeln!(file, "{}", line).map_err(|e| LogyError::io(&spill_filename, e))?;
+            }
+
+            self.memory -= trace.size;
+            trace.size = 0;
+            trace.lines.clear();
+        }
+        Ok(())
+    }
+
+    /// close all remained traces, returns durations of all traces when stats are wanted
+    fn finish(mut self) -> Result<Option<LatencyStats>> {
+        while let Some((end_time, trace_id)) = self.activities.iter().next().cloned() {
+            self.activities.remove(&(end_time, trace_id.clone()));
+            self.close(&trace_id)?;
+        }
+        self.writer.flush()?;
+
+        info!(
+            "{} traces collected, {} traces cost time over than {} ms",
+            self.trace_count, self.long_trace_count, self.options.min_cost_time
         );
-        lines.push("\n".repeat(3));
+        Ok(self.stats.take())
+    }
+}
 
-        let log_hour = duration.end_time / Duration::hours(1).num_milliseconds();
-        // write log
-        writer.write(log_hour, &lines.join("\n"));
-        grouped_logs.remove(trace_id);
-        long_duration_logs.remove(trace_id);
+impl Drop for TraceCollector<'_> {
+    /// remove spilled files left by traces not closed, like when aborted
+    fn drop(&mut self) {
+        if self.spill_count > 0 {
+            if let Err(e) = fs::remove_dir_all(&self.spill_dir) {
+                debug!("fail to remove {}: {}", self.spill_dir.to_string_lossy(), e);
+            }
+        }
     }
 }
+
+/// write logs of trace, spilled logs are streamed from disk
+fn write_long_logs(
+    writer: &mut WrappedFileWriter,
+    duration: &LogDuration,
+    unfinished: bool,
+    trace: &TraceBuffer,
+) -> Result<()> {
+    let header = format!(
+        "========================= {}{} =========================",
+        Duration::milliseconds(duration.end_time - duration.start_time),
+        if unfinished { " unfinished" } else { "" }
+    );
+    let spilled = match &trace.spill_file {
+        Some(spill_file) => {
+            let filename = spill_file.to_string_lossy().to_string();
+            let file = File::open(spill_file).map_err(|e| LogyError::io(&filename, e))?;
+            Some(
+                BufReader::new(file)
+                    .lines()
+                    .map(move |line| line.map_err(|e| LogyError::io(&filename, e))),
+            )
+        }
+        None => None,
+    };
+    let lines = iter::once(Ok(header))
+        .chain(spilled.into_iter().flatten())
+        .chain(trace.lines.iter().map(|line| Ok(line.to_string())))
+        .chain(iter::once(Ok("\n".repeat(3))));
+
+    writer.write_lines(duration.end_time, lines)
+}
@@ -0,0 +1,31 @@
+use log::debug;
+
+use super::errors::{LogyError, Result};
+use super::models::{Log, NextLogLineFinder, WrappedFileReader};
+
+/// train a zstd dictionary of at most `max_size` bytes with logs of files as samples
+///
+/// Samples are taken from the head of files up to 100 times of dictionary size, as recommended by
+/// zstd, so big files are not read to the end.
+pub fn train_dictionary(files: &[&str], pattern: &str, max_size: usize) -> Result<Vec<u8>> {
+    let sample_limit = max_size.saturating_mul(100);
+    let mut samples = Vec::new();
+    let mut sample_size = 0;
+    'files: for file in files {
+        let mut reader = WrappedFileReader::new(file, pattern, None)?;
+        while let Log::Line(log) = reader.next_log()? {
+            sample_size += log.len() + 1;
+            samples.push(log + "\n");
+            if sample_size >= sample_limit {
+                break 'files;
+            }
+        }
+    }
+    debug!(
+        "train dictionary with {} logs of {} bytes",
+        samples.len(),
+        sample_size
+    );
+
+    zstd::dict::from_samples(&samples, max_size).map_err(LogyError::dictionary)
+}
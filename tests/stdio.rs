@@ -0,0 +1,95 @@
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+const PREFIX: &str = r"^(\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3})";
+const LOGS: &str = "2021-09-27 01:00:00.100 [t1] start\n\
+                    2021-09-27 01:00:00.200 [t2] start\n  at stack\n\
+                    2021-09-27 01:00:02.100 [t1] end\n";
+
+/// run logy with input piped to stdin
+fn logy(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_logy"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_reduce_stdin_to_stdout() {
+    let output = logy(&["reduce", "-p", PREFIX, "-o", "-", "-"], LOGS);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), LOGS);
+}
+
+#[test]
+fn test_trace_report_to_stdout() {
+    let output = logy(
+        &[
+            "trace",
+            "-p",
+            PREFIX,
+            "-g",
+            r"\[(\w+)\]",
+            "-d",
+            "1000",
+            "-r",
+            "csv",
+            "-o",
+            "-",
+            "-",
+        ],
+        LOGS,
+    );
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    let lines = report.lines().collect::<Vec<&str>>();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("trace_id,"));
+    assert!(lines[1].starts_with("t1,"));
+}
+
+#[test]
+fn test_trace_stats_to_stderr() {
+    let output = logy(
+        &[
+            "trace",
+            "-p",
+            PREFIX,
+            "-g",
+            r"\[(\w+)\]",
+            "-d",
+            "1000",
+            "--stats",
+            "-o",
+            "-",
+            "-",
+        ],
+        LOGS,
+    );
+    assert!(output.status.success());
+    // stats are shown without RUST_LOG and kept out of traced logs
+    let stats = String::from_utf8(output.stderr).unwrap();
+    assert!(stats.contains("count: 2\n"), "{}", stats);
+    assert!(!String::from_utf8(output.stdout).unwrap().contains("count:"));
+}
+
+#[test]
+fn test_tail_rejects_stdin() {
+    let output = logy(&["tail", "-p", PREFIX, "-"], LOGS);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("invalid value of files: -"));
+}